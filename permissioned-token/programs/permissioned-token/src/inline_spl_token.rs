@@ -8,6 +8,17 @@ use {
     solana_program::{program_error::ProgramError, program_option::COption, pubkey::Pubkey},
 };
 
+solana_program::declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+const ACCOUNT_SIZE: usize = 165;
+const MULTISIG_SIZE: usize = 355;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+const ACCOUNT_STATE_FROZEN: u8 = 2;
+const EXTENSION_TYPE_UNINITIALIZED: u16 = 0;
+const EXTENSION_TYPE_NON_TRANSFERABLE: u16 = 9;
+const EXTENSION_TYPE_TRANSFER_HOOK_ACCOUNT: u16 = 15;
+
 fn unpack_coption_key(src: &[u8; 36]) -> Result<COption<Pubkey>, ProgramError> {
     let (tag, body) = array_refs![src, 4, 32];
     match *tag {
@@ -28,13 +39,100 @@ pub fn get_mint_authority(account_data: &[u8]) -> Result<COption<Pubkey>, Progra
     }
 }
 
+/// Extract the mint from the account bytes
+pub fn get_account_mint(account_data: &[u8]) -> Result<Pubkey, ProgramError> {
+    if account_data.len() < ACCOUNT_SIZE {
+        Err(ProgramError::InvalidAccountData)
+    } else {
+        let mint = array_ref![account_data, 0, 32];
+        Ok(Pubkey::new_from_array(*mint))
+    }
+}
+
 /// Extract the owner from the account bytes
 pub fn get_account_owner(account_data: &[u8]) -> Result<Pubkey, ProgramError> {
-    const ACCOUNT_SIZE: usize = 165;
     if account_data.len() < ACCOUNT_SIZE {
         Err(ProgramError::InvalidAccountData)
     } else {
         let owner = array_ref![account_data, 32, 32];
-        Ok(Pubkey::new(owner))
+        Ok(Pubkey::new_from_array(*owner))
+    }
+}
+
+/// Extract the amount from the account bytes
+pub fn get_account_amount(account_data: &[u8]) -> Result<u64, ProgramError> {
+    if account_data.len() < ACCOUNT_SIZE {
+        Err(ProgramError::InvalidAccountData)
+    } else {
+        let amount = array_ref![account_data, 64, 8];
+        Ok(u64::from_le_bytes(*amount))
+    }
+}
+
+/// Whether the account bytes are those of a token account, as opposed to a mint or a multisig
+pub fn is_token_account(account_data: &[u8]) -> bool {
+    account_data.len() == ACCOUNT_SIZE
+        || (account_data.len() > ACCOUNT_SIZE
+            && account_data.len() != MULTISIG_SIZE
+            && account_data[ACCOUNT_SIZE] == ACCOUNT_TYPE_ACCOUNT)
+}
+
+/// Whether the token account is frozen, from the account bytes
+pub fn is_account_frozen(account_data: &[u8]) -> Result<bool, ProgramError> {
+    if account_data.len() < ACCOUNT_SIZE {
+        Err(ProgramError::InvalidAccountData)
+    } else {
+        Ok(account_data[108] == ACCOUNT_STATE_FROZEN)
+    }
+}
+
+/// Read the `transferring` flag of the TransferHookAccount extension, token-2022 only sets it
+/// while the transfer hook is invoked
+pub fn is_account_transferring(account_data: &[u8]) -> Result<bool, ProgramError> {
+    let extension = get_extension(
+        account_data,
+        ACCOUNT_TYPE_ACCOUNT,
+        EXTENSION_TYPE_TRANSFER_HOOK_ACCOUNT,
+    )?;
+    Ok(extension.and_then(|value| value.first()) == Some(&1))
+}
+
+/// Whether the mint has the NonTransferable extension, from the mint bytes
+pub fn is_mint_non_transferable(mint_data: &[u8]) -> Result<bool, ProgramError> {
+    let extension = get_extension(
+        mint_data,
+        ACCOUNT_TYPE_MINT,
+        EXTENSION_TYPE_NON_TRANSFERABLE,
+    )?;
+    Ok(extension.is_some())
+}
+
+/// Find the value of an extension of a mint or token account, `None` if it is not initialized
+fn get_extension(
+    account_data: &[u8],
+    account_type: u8,
+    extension_type: u16,
+) -> Result<Option<&[u8]>, ProgramError> {
+    if account_data.len() <= ACCOUNT_SIZE || account_data[ACCOUNT_SIZE] != account_type {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The extensions follow the account type as (type: u16, length: u16, value) entries
+    let mut tlv_data = &account_data[ACCOUNT_SIZE + 1..];
+    while tlv_data.len() >= 4 {
+        let entry_type = u16::from_le_bytes([tlv_data[0], tlv_data[1]]);
+        let length = u16::from_le_bytes([tlv_data[2], tlv_data[3]]) as usize;
+        if entry_type == EXTENSION_TYPE_UNINITIALIZED {
+            break;
+        }
+        let value = tlv_data
+            .get(4..4 + length)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        if entry_type == extension_type {
+            return Ok(Some(value));
+        }
+        tlv_data = &tlv_data[4 + length..];
     }
+
+    Ok(None)
 }
@@ -1,31 +1,354 @@
-use anchor_lang::prelude::*;
+use anchor_lang::{prelude::*, system_program};
 
 use anchor_lang::solana_program::{
-    account_info::AccountInfo, program_error::PrintProgramError, pubkey::Pubkey,
+    account_info::AccountInfo, program_error::PrintProgramError, program_option::COption,
+    pubkey::Pubkey,
+};
+use spl_tlv_account_resolution::{account::ExtraAccountMeta, state::ExtraAccountMetaList};
+use spl_transfer_hook_interface::{
+    error::TransferHookError, get_extra_account_metas_address, instruction::ExecuteInstruction,
 };
-use spl_transfer_hook_interface::error::TransferHookError;
 
 mod inline_spl_token;
+pub mod merkle;
 mod processor;
 
 pub const PERMISSION_REGISTRY_SEED: &[u8] = b"permission-registry";
+pub const PERMISSION_REQUEST_SEED: &[u8] = b"permission-request";
+pub const ROLE_REGISTRY_SEED: &[u8] = b"role-registry";
+pub const TRANSFER_POLICY_SEED: &[u8] = b"transfer-policy";
+pub const APPROVED_PROGRAMS_SEED: &[u8] = b"approved-programs";
+
+/// Duration of the daily volume window, in seconds
+pub const VOLUME_DAY: i64 = 24 * 60 * 60;
+/// Duration of the monthly volume window, in seconds
+pub const VOLUME_MONTH: i64 = 30 * VOLUME_DAY;
+
+/// Share of the reclaimed rent paid to whoever prunes an expired permission, in percent
+pub const PRUNE_BOUNTY_PERCENT: u64 = 10;
+
+/// Maximum number of counterparties a holder can be restricted to
+pub const MAX_COUNTERPARTIES: usize = 4;
+
+/// Maximum number of locked lots kept per holder, further lots are merged into the last one of the
+/// same token account
+pub const MAX_LOCKED_LOTS: usize = 8;
+
+/// Maximum number of balance checkpoints kept per holder, the oldest one is dropped first
+pub const MAX_CHECKPOINTS: usize = 8;
 
 declare_id!("PermissionedToken11111111111111111111111112");
 
+/// Address of the permission registry governing `mint`
+pub fn get_permission_registry_address(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[PERMISSION_REGISTRY_SEED, mint.as_ref()], &ID).0
+}
+
+/// Address of the roles granted in `permission_registry`
+pub fn get_role_registry_address(permission_registry: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[ROLE_REGISTRY_SEED, permission_registry.as_ref()], &ID).0
+}
+
+/// Address of the transfer rules of `permission_registry`
+pub fn get_transfer_policy_address(permission_registry: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[TRANSFER_POLICY_SEED, permission_registry.as_ref()], &ID).0
+}
+
+/// Address of the programs whose PDAs can hold tokens in `permission_registry`
+pub fn get_approved_programs_address(permission_registry: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[APPROVED_PROGRAMS_SEED, permission_registry.as_ref()], &ID).0
+}
+
+/// Address of the permission account of `owner` in `permission_registry`
+pub fn get_permission_address(permission_registry: &Pubkey, owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[permission_registry.as_ref(), owner.as_ref()], &ID).0
+}
+
+/// Address of the pending permission request of `owner` in `permission_registry`
+pub fn get_permission_request_address(permission_registry: &Pubkey, owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            PERMISSION_REQUEST_SEED,
+            permission_registry.as_ref(),
+            owner.as_ref(),
+        ],
+        &ID,
+    )
+    .0
+}
+
+/// Owner and balance of `token_account`, which has to be a token account of `mint`
+fn read_token_account(token_account: &AccountInfo, mint: &Pubkey) -> Result<(Pubkey, u64)> {
+    let data = token_account.try_borrow_data()?;
+    require!(
+        *token_account.owner == inline_spl_token::ID
+            && inline_spl_token::get_account_mint(&data)? == *mint,
+        ErrorCode::IncorrectTokenAccount
+    );
+    Ok((
+        inline_spl_token::get_account_owner(&data)?,
+        inline_spl_token::get_account_amount(&data)?,
+    ))
+}
+
+/// Balance of `token_account`, which has to be a token account of `mint` owned by `owner`
+fn get_holder_balance(token_account: &AccountInfo, mint: &Pubkey, owner: &Pubkey) -> Result<u64> {
+    let (token_account_owner, balance) = read_token_account(token_account, mint)?;
+    require_keys_eq!(
+        token_account_owner,
+        *owner,
+        ErrorCode::IncorrectTokenAccount
+    );
+    Ok(balance)
+}
+
+/// Emit `PermissionAdded` for a permission created by the instruction, `PermissionUpdated`
+/// otherwise
+fn emit_permission_written(permission_registry: Pubkey, permission: &Permission, added: bool) {
+    if added {
+        emit!(PermissionAdded {
+            permission_registry,
+            owner: permission.owner,
+            allowed_send: permission.allowed_send,
+            allowed_receive: permission.allowed_receive,
+            expire_at: permission.expire_at,
+        });
+    } else {
+        emit!(PermissionUpdated {
+            permission_registry,
+            owner: permission.owner,
+            allowed_send: permission.allowed_send,
+            allowed_receive: permission.allowed_receive,
+            expire_at: permission.expire_at,
+        });
+    }
+}
+
+/// Transfer of `amount` from the source to the destination of `accounts`, as the transfer hook
+/// would see it
+fn read_transfer(accounts: &CheckTransfer, amount: u64) -> Result<processor::Transfer> {
+    let mint = accounts.permission_registry.mint;
+    let (sender, source_balance_before) = read_token_account(&accounts.source, &mint)?;
+    let (receiver, destination_balance_before) = read_token_account(&accounts.destination, &mint)?;
+    let source_balance = if accounts.source.key() == accounts.destination.key() {
+        source_balance_before
+    } else {
+        source_balance_before.saturating_sub(amount)
+    };
+    Ok(processor::Transfer {
+        source: accounts.source.key(),
+        destination: accounts.destination.key(),
+        sender,
+        receiver,
+        authority: accounts.authority.key(),
+        amount,
+        source_balance_before,
+        source_balance,
+        destination_balance_before,
+    })
+}
+
 #[program]
 pub mod permissioned_token {
     use super::*;
 
+    /// Create the permission registry of a mint, the mint authority has to approve it
     pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+        let mint_authority =
+            inline_spl_token::get_mint_authority(&ctx.accounts.mint.try_borrow_data()?)?;
+        require!(
+            mint_authority == COption::Some(ctx.accounts.mint_authority.key()),
+            ErrorCode::IncorrectMintAuthority
+        );
+
         ctx.accounts
             .permission_registry
             .set_inner(PermissionRegistry {
                 authority: ctx.accounts.authority.key(),
-                permissions: vec![],
+                pending_authority: None,
+                mint: ctx.accounts.mint.key(),
+                grace_period: 0,
+                lockup_period: 0,
+                mode: RegistryMode::Allowlist,
+                paused: false,
+                owner_signed_only: false,
+                merkle_root: None,
+                has_transfer_rules: false,
+                track_holders: false,
+                holder_count: 0,
+                max_holders: None,
+                snapshot_id: 0,
+                credential_mint: None,
+                kyc_provider: None,
             });
         Ok(())
     }
 
+    /// Switch between allowing only permitted holders and allowing everyone but blocked holders
+    pub fn set_registry_mode(ctx: Context<SetRegistryMode>, mode: RegistryMode) -> Result<()> {
+        ctx.accounts.permission_registry.mode = mode;
+        Ok(())
+    }
+
+    /// Halt every transfer of the mint
+    pub fn pause(ctx: Context<SetPaused>) -> Result<()> {
+        ctx.accounts.permission_registry.paused = true;
+        Ok(())
+    }
+
+    pub fn unpause(ctx: Context<SetPaused>) -> Result<()> {
+        ctx.accounts.permission_registry.paused = false;
+        Ok(())
+    }
+
+    /// Maintain the holder index in the permission accounts and the registry, the registry and
+    /// the permissions then have to be writable extra accounts. Transfers making a new holder are
+    /// rejected once there are `max_holders` holders
+    pub fn set_holder_tracking(
+        ctx: Context<SetHolderTracking>,
+        track_holders: bool,
+        max_holders: Option<u64>,
+    ) -> Result<()> {
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        permission_registry.track_holders = track_holders;
+        permission_registry.max_holders = max_holders;
+        Ok(())
+    }
+
+    /// Refresh the holder index entry of `owner` with the total balance of its token accounts,
+    /// passed as remaining accounts, for tokens minted or burned outside of transfers. The
+    /// permission manager has to pass every token account of the holder
+    pub fn sync_holder(ctx: Context<SyncHolder>, owner: Pubkey) -> Result<()> {
+        let mint = ctx.accounts.permission_registry.mint;
+        let mut balance: u64 = 0;
+        for (index, token_account) in ctx.remaining_accounts.iter().enumerate() {
+            require!(
+                ctx.remaining_accounts[..index]
+                    .iter()
+                    .all(|other| other.key != token_account.key),
+                ErrorCode::IncorrectTokenAccount
+            );
+            balance = balance.saturating_add(get_holder_balance(token_account, &mint, &owner)?);
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        if permission_registry.track_holders {
+            permission_registry.set_holder_balance(
+                &mut ctx.accounts.permission,
+                balance,
+                now,
+                false,
+            )?;
+        }
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Lock the tokens received by a holder for `lockup_period` seconds, zero disables lockups
+    pub fn set_lockup_period(ctx: Context<SetLockupPeriod>, lockup_period: i64) -> Result<()> {
+        require_gte!(lockup_period, 0, ErrorCode::InvalidLockupPeriod);
+        ctx.accounts.permission_registry.lockup_period = lockup_period;
+        Ok(())
+    }
+
+    /// Accept holding a token of `credential_mint` in place of a permission, the mint has to be a
+    /// non-transferable token-2022 mint whose mint authority is `kyc_provider`
+    pub fn set_credential_mint(
+        ctx: Context<SetCredentialMint>,
+        kyc_provider: Pubkey,
+    ) -> Result<()> {
+        let credential_mint = &ctx.accounts.credential_mint;
+        let data = credential_mint.try_borrow_data()?;
+        require!(
+            *credential_mint.owner == inline_spl_token::ID
+                && inline_spl_token::is_mint_non_transferable(&data)?
+                && inline_spl_token::get_mint_authority(&data)? == COption::Some(kyc_provider),
+            ErrorCode::InvalidCredentialMint
+        );
+
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        permission_registry.credential_mint = Some(credential_mint.key());
+        permission_registry.kyc_provider = Some(kyc_provider);
+        Ok(())
+    }
+
+    pub fn remove_credential_mint(ctx: Context<RemoveCredentialMint>) -> Result<()> {
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        permission_registry.credential_mint = None;
+        permission_registry.kyc_provider = None;
+        Ok(())
+    }
+
+    /// Reject transfers made by a delegate or permanent delegate instead of the owner
+    pub fn set_owner_signed_only(
+        ctx: Context<SetOwnerSignedOnly>,
+        owner_signed_only: bool,
+    ) -> Result<()> {
+        ctx.accounts.permission_registry.owner_signed_only = owner_signed_only;
+        Ok(())
+    }
+
+    /// Keep accepting expired permissions for `grace_period` seconds, leaving time to renew them
+    pub fn set_grace_period(ctx: Context<SetGracePeriod>, grace_period: i64) -> Result<()> {
+        require_gte!(grace_period, 0, ErrorCode::InvalidGracePeriod);
+        ctx.accounts.permission_registry.grace_period = grace_period;
+        Ok(())
+    }
+
+    /// First step of handing the registry over, `new_authority` has to accept it
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        ctx.accounts.permission_registry.pending_authority = Some(new_authority);
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        require!(
+            permission_registry.pending_authority == Some(ctx.accounts.new_authority.key()),
+            ErrorCode::IncorrectPendingAuthority
+        );
+        permission_registry.authority = ctx.accounts.new_authority.key();
+        permission_registry.pending_authority = None;
+        Ok(())
+    }
+
+    pub fn cancel_authority_transfer(ctx: Context<CancelAuthorityTransfer>) -> Result<()> {
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        require!(
+            permission_registry.pending_authority.is_some(),
+            ErrorCode::NoPendingAuthority
+        );
+        permission_registry.pending_authority = None;
+        Ok(())
+    }
+
+    /// Give `role` to `member`, the role registry grows to store it
+    pub fn grant_role(ctx: Context<GrantRole>, member: Pubkey, role: Role) -> Result<()> {
+        let roles = &mut ctx.accounts.role_registry.roles;
+        require!(
+            !roles.contains(&RoleAssignment { member, role }),
+            ErrorCode::RoleAlreadyGranted
+        );
+        roles.push(RoleAssignment { member, role });
+        Ok(())
+    }
+
+    /// Take `role` back from `member`, the freed rent goes back to the authority
+    pub fn revoke_role(ctx: Context<RevokeRole>, member: Pubkey, role: Role) -> Result<()> {
+        let roles = &mut ctx.accounts.role_registry.roles;
+        let index = roles
+            .iter()
+            .position(|assignment| *assignment == RoleAssignment { member, role })
+            .ok_or(ErrorCode::RoleNotGranted)?;
+        roles.remove(index);
+        Ok(())
+    }
+
     pub fn add_permission(
         ctx: Context<AddPermission>,
         allowed_send: bool,
@@ -33,11 +356,15 @@ pub mod permissioned_token {
         expire_at: i64,
         owner: Pubkey,
     ) -> Result<()> {
-        let permissions = &mut ctx.accounts.permission_registry.permissions;
-        for permission in permissions.iter() {
-            require_keys_neq!(permission.owner, owner);
-        }
-        permissions.push(Permission {
+        ctx.accounts.permission.set_inner(Permission::new(
+            owner,
+            allowed_send,
+            allowed_receive,
+            expire_at,
+        ));
+
+        emit!(PermissionAdded {
+            permission_registry: ctx.accounts.permission_registry.key(),
             owner,
             allowed_send,
             allowed_receive,
@@ -46,27 +373,567 @@ pub mod permissioned_token {
         Ok(())
     }
 
+    /// Ask for a permission, `reference_hash` commits to the off-chain KYC documents of the
+    /// holder. The request stays pending until a permission manager approves or rejects it
+    pub fn request_permission(
+        ctx: Context<RequestPermission>,
+        allowed_send: bool,
+        allowed_receive: bool,
+        reference_hash: Option<[u8; 32]>,
+    ) -> Result<()> {
+        ctx.accounts
+            .permission_request
+            .set_inner(PermissionRequest {
+                owner: ctx.accounts.owner.key(),
+                allowed_send,
+                allowed_receive,
+                reference_hash,
+                requested_at: Clock::get()?.unix_timestamp,
+            });
+
+        emit!(PermissionRequested {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner: ctx.accounts.owner.key(),
+            allowed_send,
+            allowed_receive,
+            reference_hash,
+        });
+        Ok(())
+    }
+
+    /// Turn the pending request of `owner` into a permission expiring at `expire_at`, the rent of
+    /// the request goes back to the holder
+    pub fn approve_request(
+        ctx: Context<ApproveRequest>,
+        expire_at: i64,
+        owner: Pubkey,
+    ) -> Result<()> {
+        let request = &ctx.accounts.permission_request;
+        ctx.accounts.permission.set_inner(Permission::new(
+            owner,
+            request.allowed_send,
+            request.allowed_receive,
+            expire_at,
+        ));
+
+        emit!(PermissionAdded {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner,
+            allowed_send: request.allowed_send,
+            allowed_receive: request.allowed_receive,
+            expire_at,
+        });
+        Ok(())
+    }
+
+    /// Close the pending request of `owner`, the rent goes back to the holder
+    pub fn reject_request(ctx: Context<RejectRequest>, owner: Pubkey) -> Result<()> {
+        emit!(PermissionRequestRejected {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner,
+        });
+        Ok(())
+    }
+
+    /// Overwrite the flags and expiry of the permission of `owner`, a proven permission is taken
+    /// over and no longer depends on the Merkle root
     pub fn update_permission(
-        ctx: Context<AddPermission>,
+        ctx: Context<UpdatePermission>,
         allowed_send: bool,
         allowed_receive: bool,
         expire_at: i64,
         owner: Pubkey,
     ) -> Result<()> {
-        let permissions = &mut ctx.accounts.permission_registry.permissions;
-        let permission = permissions
-            .iter_mut()
-            .find(|permission| permission.owner == owner)
-            .ok_or(ErrorCode::MissingPermission)?;
+        let permission = &mut ctx.accounts.permission;
+        permission.allowed_send = allowed_send;
+        permission.allowed_receive = allowed_receive;
+        permission.expire_at = expire_at;
+        permission.merkle_root = None;
+
+        emit!(PermissionUpdated {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner,
+            allowed_send,
+            allowed_receive,
+            expire_at,
+        });
+        Ok(())
+    }
+
+    /// Replace the rules restricting transfers by the attributes of the holders, any transfer
+    /// between permitted holders is allowed when there are no rules
+    pub fn set_transfer_policy(
+        ctx: Context<SetTransferPolicy>,
+        transfer_rules: Vec<TransferRule>,
+    ) -> Result<()> {
+        ctx.accounts.permission_registry.has_transfer_rules = !transfer_rules.is_empty();
+        ctx.accounts.transfer_policy.transfer_rules = transfer_rules;
+        Ok(())
+    }
+
+    pub fn set_holder_attributes(
+        ctx: Context<SetHolderAttributes>,
+        attributes: HolderAttributes,
+        _owner: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.permission.attributes = attributes;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Allow `owner` to transfer the tokens of other holders as their delegate
+    pub fn set_delegate_permission(
+        ctx: Context<SetDelegatePermission>,
+        allowed_delegate: bool,
+        _owner: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.permission.allowed_delegate = allowed_delegate;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Restrict `owner` to sending to and receiving from `counterparties`, an empty list lifts
+    /// the restriction
+    pub fn set_counterparties(
+        ctx: Context<SetCounterparties>,
+        counterparties: Vec<Pubkey>,
+        _owner: Pubkey,
+    ) -> Result<()> {
+        require_gte!(
+            MAX_COUNTERPARTIES,
+            counterparties.len(),
+            ErrorCode::TooManyCounterparties
+        );
+        ctx.accounts.permission.counterparties = counterparties;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Commit to a Merkle tree of permissions, see `merkle`. Permissions proven against a
+    /// previous root stop working
+    pub fn set_merkle_root(
+        ctx: Context<SetMerkleRoot>,
+        merkle_root: Option<[u8; 32]>,
+    ) -> Result<()> {
+        ctx.accounts.permission_registry.merkle_root = merkle_root;
+        Ok(())
+    }
+
+    /// Permissionless instruction materializing the permission of `owner` from a proof against
+    /// the registry Merkle root, the limits of an existing proven permission are kept. Permissions
+    /// written by a permission manager are never overwritten, a proven holder is revoked by
+    /// rotating the root or by updating its permission
+    pub fn prove_permission(
+        ctx: Context<ProvePermission>,
+        allowed_send: bool,
+        allowed_receive: bool,
+        expire_at: i64,
+        owner: Pubkey,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let merkle_root = ctx
+            .accounts
+            .permission_registry
+            .merkle_root
+            .ok_or(ErrorCode::MerkleRootNotSet)?;
+        let leaf = merkle::compute_leaf(&owner, allowed_send, allowed_receive, expire_at);
+        require!(
+            merkle::verify_proof(&merkle_root, leaf, &proof),
+            ErrorCode::InvalidMerkleProof
+        );
 
+        let permission = &mut ctx.accounts.permission;
+        let added = permission.is_new();
+        require!(
+            added || permission.merkle_root.is_some(),
+            ErrorCode::PermissionAlreadyExists
+        );
+        permission.owner = owner;
         permission.allowed_send = allowed_send;
         permission.allowed_receive = allowed_receive;
         permission.expire_at = expire_at;
+        permission.merkle_root = Some(merkle_root);
+
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            added,
+        );
+        Ok(())
+    }
+
+    /// Replace the programs whose PDAs can hold tokens, permissions approved for the PDAs of a
+    /// removed program stop working
+    pub fn set_approved_programs(
+        ctx: Context<SetApprovedPrograms>,
+        programs: Vec<Pubkey>,
+    ) -> Result<()> {
+        ctx.accounts.approved_programs.programs = programs;
+        Ok(())
+    }
+
+    /// Permissionless instruction allowing `owner` to send and receive when it is the PDA of an
+    /// approved program derived from `seeds`, the bump included. Pools and vaults of approved
+    /// venues can hold tokens without being added one by one. An existing permission is left as
+    /// a permission manager set it
+    pub fn approve_program_holder(
+        ctx: Context<ApproveProgramHolder>,
+        owner: Pubkey,
+        program_id: Pubkey,
+        seeds: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts
+                .approved_programs
+                .programs
+                .contains(&program_id),
+            ErrorCode::ProgramNotApproved
+        );
+        let seeds = seeds.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        require!(
+            Pubkey::create_program_address(&seeds, &program_id) == Ok(owner),
+            ErrorCode::InvalidProgramHolderSeeds
+        );
+
+        let permission = &mut ctx.accounts.permission;
+        if !permission.is_new() {
+            require!(
+                permission.program_owner == Some(program_id),
+                ErrorCode::PermissionAlreadyExists
+            );
+            return Ok(());
+        }
+        permission.set_inner(Permission {
+            program_owner: Some(program_id),
+            ..Permission::new(owner, true, true, i64::MAX)
+        });
+
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            true,
+        );
+        Ok(())
+    }
+
+    /// Reject transfers from and to `owner` whatever the mode, the permission account is
+    /// created if needed without any permission and never expires
+    pub fn add_block(ctx: Context<AddBlock>, owner: Pubkey) -> Result<()> {
+        let permission = &mut ctx.accounts.permission;
+        let added = permission.is_new();
+        if added {
+            permission.set_inner(Permission::new(owner, false, false, i64::MAX));
+        }
+        permission.blocked = true;
+
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            added,
+        );
+        Ok(())
+    }
+
+    pub fn remove_block(ctx: Context<RemoveBlock>, _owner: Pubkey) -> Result<()> {
+        ctx.accounts.permission.blocked = false;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Halt the transfers from and to `owner`, the permission account is created if needed
+    /// without any permission and never expires
+    pub fn freeze_holder(ctx: Context<FreezeHolder>, owner: Pubkey) -> Result<()> {
+        let permission = &mut ctx.accounts.permission;
+        let added = permission.is_new();
+        if added {
+            permission.set_inner(Permission::new(owner, false, false, i64::MAX));
+        }
+        permission.frozen = true;
+
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            added,
+        );
+        Ok(())
+    }
+
+    pub fn thaw_holder(ctx: Context<ThawHolder>, _owner: Pubkey) -> Result<()> {
+        ctx.accounts.permission.frozen = false;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Cap the amounts `owner` can send and receive, the volume already transferred in the current
+    /// windows is kept
+    pub fn set_transfer_limits(
+        ctx: Context<SetTransferLimits>,
+        send_limits: TransferLimits,
+        receive_limits: TransferLimits,
+        _owner: Pubkey,
+    ) -> Result<()> {
+        let permission = &mut ctx.accounts.permission;
+        permission.send_limits = send_limits;
+        permission.receive_limits = receive_limits;
+
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Lock `amount` of the tokens of `owner` in one of its token accounts until `unlock_at`, for
+    /// tokens minted to the holder which do not go through the transfer hook
+    pub fn grant_lockup(
+        ctx: Context<GrantLockup>,
+        amount: u64,
+        unlock_at: i64,
+        owner: Pubkey,
+    ) -> Result<()> {
+        get_holder_balance(
+            &ctx.accounts.token_account,
+            &ctx.accounts.permission_registry.mint,
+            &owner,
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts
+            .permission
+            .lock(ctx.accounts.token_account.key(), amount, unlock_at, now)?;
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Open a new snapshot, the balances of the holders at this point can be queried with
+    /// `get_snapshot_balance`
+    pub fn create_snapshot(ctx: Context<CreateSnapshot>) -> Result<()> {
+        let permission_registry = &mut ctx.accounts.permission_registry;
+        permission_registry.snapshot_id += 1;
+
+        emit!(SnapshotCreated {
+            permission_registry: permission_registry.key(),
+            snapshot_id: permission_registry.snapshot_id,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    /// Record the balance of a token account of `owner` for the current snapshot, to be called by
+    /// a permission manager before minting or burning tokens in the account
+    pub fn record_checkpoint(ctx: Context<RecordCheckpoint>, owner: Pubkey) -> Result<()> {
+        let balance = get_holder_balance(
+            &ctx.accounts.token_account,
+            &ctx.accounts.permission_registry.mint,
+            &owner,
+        )?;
+        ctx.accounts.permission_registry.checkpoint_holder(
+            Some(&mut ctx.accounts.permission),
+            ctx.accounts.token_account.key(),
+            balance,
+        );
+        emit_permission_written(
+            ctx.accounts.permission_registry.key(),
+            &ctx.accounts.permission,
+            false,
+        );
+        Ok(())
+    }
+
+    /// Return the balance of a token account of `owner` when `snapshot_id` was created
+    pub fn get_snapshot_balance(
+        ctx: Context<GetSnapshotBalance>,
+        owner: Pubkey,
+        snapshot_id: u64,
+    ) -> Result<u64> {
+        require!(
+            (1..=ctx.accounts.permission_registry.snapshot_id).contains(&snapshot_id),
+            ErrorCode::InvalidSnapshotId
+        );
+        let balance = get_holder_balance(
+            &ctx.accounts.token_account,
+            &ctx.accounts.permission_registry.mint,
+            &owner,
+        )?;
+        Ok(ctx
+            .accounts
+            .permission
+            .snapshot_balance(snapshot_id, &ctx.accounts.token_account.key())?
+            .unwrap_or(balance))
+    }
+
+    /// Return the balance of a token account of `owner` minus the tokens still locked in it
+    pub fn get_unlocked_balance(ctx: Context<GetUnlockedBalance>, owner: Pubkey) -> Result<u64> {
+        let balance = get_holder_balance(
+            &ctx.accounts.token_account,
+            &ctx.accounts.permission_registry.mint,
+            &owner,
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+        Ok(balance.saturating_sub(
+            ctx.accounts
+                .permission
+                .locked_amount(&ctx.accounts.token_account.key(), now),
+        ))
+    }
+
+    /// Preflight a transfer of `amount` from `source` to `destination` signed by `authority`,
+    /// without any effect. Always succeeds and returns the verdict of the transfer hook, the
+    /// permissions of the holders and of the authority, and the credential mint and token
+    /// accounts, are passed as remaining accounts
+    pub fn check_transfer(ctx: Context<CheckTransfer>, amount: u64) -> Result<TransferVerdict> {
+        let transfer = match read_transfer(ctx.accounts, amount) {
+            Ok(transfer) => transfer,
+            Err(error) => {
+                return Ok(TransferVerdict {
+                    allowed: false,
+                    error_code: Some(ProgramError::from(error).into()),
+                    expire_at: None,
+                })
+            }
+        };
+        let permission_registry_address = ctx.accounts.permission_registry.key();
+        let expire_at = processor::earliest_expiry(
+            ctx.program_id,
+            &permission_registry_address,
+            &[transfer.sender, transfer.receiver, transfer.authority],
+            ctx.remaining_accounts,
+        );
+
+        let mut permission_registry = PermissionRegistry::clone(&ctx.accounts.permission_registry);
+        let error_code = processor::check_transfer(
+            ctx.program_id,
+            &permission_registry_address,
+            &mut permission_registry,
+            &transfer,
+            ctx.remaining_accounts,
+            false,
+        )
+        .err()
+        .map(u64::from);
+        Ok(TransferVerdict {
+            allowed: error_code.is_none(),
+            error_code,
+            expire_at,
+        })
+    }
+
+    /// Close the permission account, the rent goes to the rent recipient. A holder still holding
+    /// tokens leaves the holder index
+    pub fn remove_permission(ctx: Context<RemovePermission>, owner: Pubkey) -> Result<()> {
+        if ctx.accounts.permission.balance > 0 {
+            let permission_registry = &mut ctx.accounts.permission_registry;
+            permission_registry.holder_count = permission_registry.holder_count.saturating_sub(1);
+        }
+
+        emit!(PermissionRemoved {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner,
+        });
+        Ok(())
+    }
+
+    /// Permissionless crank closing a permission past its expiry and grace period, the caller
+    /// gets a share of the rent and the rest goes back to the authority. Only permissions carrying
+    /// nothing but their flags can be pruned, see `Permission::is_prunable`
+    pub fn prune_expired(ctx: Context<PruneExpired>, owner: Pubkey) -> Result<()> {
+        let clock = Clock::get()?;
+        require!(
+            ctx.accounts.permission.is_prunable(clock.unix_timestamp),
+            ErrorCode::PermissionNotPrunable
+        );
+        require!(
+            ctx.accounts
+                .permission_registry
+                .is_expired(&ctx.accounts.permission, clock.unix_timestamp),
+            ErrorCode::PermissionNotExpired
+        );
 
+        let permission_info = ctx.accounts.permission.to_account_info();
+        let bounty = permission_info.lamports() * PRUNE_BOUNTY_PERCENT / 100;
+        **permission_info.try_borrow_mut_lamports()? -= bounty;
+        **ctx.accounts.cranker.try_borrow_mut_lamports()? += bounty;
+
+        emit!(PermissionRemoved {
+            permission_registry: ctx.accounts.permission_registry.key(),
+            owner,
+        });
         Ok(())
     }
 
-    // TODO: delete permission
+    /// Rewrite the extra accounts required by the transfer hook with the remaining accounts, the
+    /// first one being the permission registry. The permission PDAs of the holders are added by
+    /// the program, the validation account is resized and its rent settled with the payer
+    pub fn update_extra_account_metas<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpdateExtraAccountMetas<'info>>,
+    ) -> Result<()> {
+        let mint_authority =
+            inline_spl_token::get_mint_authority(&ctx.accounts.mint.try_borrow_data()?)?;
+        require!(
+            mint_authority == COption::Some(ctx.accounts.mint_authority.key()),
+            ErrorCode::IncorrectMintAuthority
+        );
+
+        let extra_account_metas = processor::with_permission_metas(
+            &ctx.accounts.mint.key(),
+            &ctx.remaining_accounts
+                .iter()
+                .map(ExtraAccountMeta::from)
+                .collect::<Vec<_>>(),
+        )?;
+        let extra_account_metas_info = ctx.accounts.extra_account_metas.to_account_info();
+        let account_size = ExtraAccountMetaList::size_of(extra_account_metas.len())?;
+        let rent_lamports = Rent::get()?.minimum_balance(account_size);
+        let current_lamports = extra_account_metas_info.lamports();
+        if rent_lamports > current_lamports {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.payer.to_account_info(),
+                        to: extra_account_metas_info.clone(),
+                    },
+                ),
+                rent_lamports - current_lamports,
+            )?;
+        } else {
+            let excess_lamports = current_lamports - rent_lamports;
+            **extra_account_metas_info.try_borrow_mut_lamports()? -= excess_lamports;
+            **ctx.accounts.payer.try_borrow_mut_lamports()? += excess_lamports;
+        }
+        extra_account_metas_info.realloc(account_size, false)?;
+
+        // The previous list has to be cleared for the TLV entry to be allocated again
+        let mut data = extra_account_metas_info.try_borrow_mut_data()?;
+        data.fill(0);
+        ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &extra_account_metas)?;
+
+        Ok(())
+    }
 
     /// The fallback allows routing methods to match the transfer hook interface
     pub fn fallback(
@@ -88,73 +955,1344 @@ pub mod permissioned_token {
 pub struct Initialize<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
-    #[account(init, seeds = [PERMISSION_REGISTRY_SEED], bump, payer = authority, space = PermissionRegistry::SPACE)]
+    pub mint_authority: Signer<'info>,
+    /// CHECK: The mint authority is read from the mint data
+    pub mint: UncheckedAccount<'info>,
+    #[account(init, seeds = [PERMISSION_REGISTRY_SEED, mint.key().as_ref()], bump, payer = authority, space = PermissionRegistry::SPACE)]
     pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(init, seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump, payer = authority, space = RoleRegistry::space(0))]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(init, seeds = [TRANSFER_POLICY_SEED, permission_registry.key().as_ref()], bump, payer = authority, space = TransferPolicy::space(0))]
+    pub transfer_policy: Account<'info, TransferPolicy>,
+    #[account(init, seeds = [APPROVED_PROGRAMS_SEED, permission_registry.key().as_ref()], bump, payer = authority, space = ApprovedPrograms::space(0))]
+    pub approved_programs: Account<'info, ApprovedPrograms>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct AddPermission<'info> {
+pub struct UpdateExtraAccountMetas<'info> {
     #[account(mut)]
+    pub payer: Signer<'info>,
+    pub mint_authority: Signer<'info>,
+    /// CHECK: The mint authority is read from the mint data
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Validation account of the transfer hook interface, created with
+    /// `InitializeExtraAccountMetaList`
+    #[account(mut, owner = ID, address = get_extra_account_metas_address(&mint.key(), &ID))]
+    pub extra_account_metas: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetRegistryMode<'info> {
     pub authority: Signer<'info>,
-    #[account(mut, has_one = authority)]
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
     pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
 }
 
-#[account]
-pub struct PermissionRegistry {
-    pub authority: Pubkey,
-    /// TODO: Zero copy extendable array storage
-    pub permissions: Vec<Permission>,
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::Pauser) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
 }
 
-impl PermissionRegistry {
-    const SPACE: usize = 8 + 32 + 4 + 10 * Permission::SPACE;
-
-    /// Validate that both sender and receiver have necessary permissions
-    fn validate_transfer(&self, sender: &Pubkey, receiver: &Pubkey) -> Result<()> {
-        let mut allowed_send = false;
-        let mut allowed_receive = false;
-        for permission in self.permissions.iter() {
-            if &permission.owner == sender {
-                require!(
-                    permission.allowed_send,
-                    ErrorCode::MissingPermissionForSender
-                );
-                allowed_send = true;
-            } else if &permission.owner == receiver {
-                require!(
-                    permission.allowed_receive,
-                    ErrorCode::MissingPermissionForReceiver
-                );
-                allowed_receive = true;
-            }
-            if allowed_send && allowed_receive {
-                return Ok(());
-            }
-        }
+#[derive(Accounts)]
+pub struct SetHolderTracking<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct SyncHolder<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+pub struct SetLockupPeriod<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct SetCredentialMint<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    /// CHECK: Non-transferable token-2022 mint, checked in the instruction
+    pub credential_mint: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveCredentialMint<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct SetOwnerSignedOnly<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct SetGracePeriod<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, has_one = authority)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    pub new_authority: Signer<'info>,
+    #[account(mut)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAuthorityTransfer<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, has_one = authority)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct GrantRole<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(
+        mut,
+        seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()],
+        bump,
+        realloc = RoleRegistry::space(role_registry.roles.len() + 1),
+        realloc::payer = authority,
+        realloc::zero = false,
+    )]
+    pub role_registry: Account<'info, RoleRegistry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeRole<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(
+        mut,
+        seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()],
+        bump,
+        realloc = RoleRegistry::space(role_registry.roles.len().saturating_sub(1)),
+        realloc::payer = authority,
+        realloc::zero = false,
+    )]
+    pub role_registry: Account<'info, RoleRegistry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(allowed_send: bool, allowed_receive: bool, expire_at: i64, owner: Pubkey)]
+pub struct AddPermission<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(init, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = authority, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RequestPermission<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(init, seeds = [PERMISSION_REQUEST_SEED, permission_registry.key().as_ref(), owner.key().as_ref()], bump, payer = owner, space = PermissionRequest::SPACE)]
+    pub permission_request: Account<'info, PermissionRequest>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(expire_at: i64, owner: Pubkey)]
+pub struct ApproveRequest<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [PERMISSION_REQUEST_SEED, permission_registry.key().as_ref(), owner.as_ref()], bump, close = requester)]
+    pub permission_request: Account<'info, PermissionRequest>,
+    #[account(init, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = authority, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Holder who made the request, receiving its rent
+    #[account(mut, address = owner)]
+    pub requester: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RejectRequest<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [PERMISSION_REQUEST_SEED, permission_registry.key().as_ref(), owner.as_ref()], bump, close = requester)]
+    pub permission_request: Account<'info, PermissionRequest>,
+    /// CHECK: Holder who made the request, receiving its rent
+    #[account(mut, address = owner)]
+    pub requester: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(allowed_send: bool, allowed_receive: bool, expire_at: i64, owner: Pubkey)]
+pub struct UpdatePermission<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(transfer_rules: Vec<TransferRule>)]
+pub struct SetTransferPolicy<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(
+        mut,
+        seeds = [TRANSFER_POLICY_SEED, permission_registry.key().as_ref()],
+        bump,
+        realloc = TransferPolicy::space(transfer_rules.len()),
+        realloc::payer = authority,
+        realloc::zero = false,
+    )]
+    pub transfer_policy: Account<'info, TransferPolicy>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(attributes: HolderAttributes, owner: Pubkey)]
+pub struct SetHolderAttributes<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(allowed_delegate: bool, owner: Pubkey)]
+pub struct SetDelegatePermission<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(counterparties: Vec<Pubkey>, owner: Pubkey)]
+pub struct SetCounterparties<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+pub struct SetMerkleRoot<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+#[instruction(allowed_send: bool, allowed_receive: bool, expire_at: i64, owner: Pubkey)]
+pub struct ProvePermission<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(init_if_needed, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = payer, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(programs: Vec<Pubkey>)]
+pub struct SetApprovedPrograms<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(
+        mut,
+        seeds = [APPROVED_PROGRAMS_SEED, permission_registry.key().as_ref()],
+        bump,
+        realloc = ApprovedPrograms::space(programs.len()),
+        realloc::payer = authority,
+        realloc::zero = false,
+    )]
+    pub approved_programs: Account<'info, ApprovedPrograms>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct ApproveProgramHolder<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [APPROVED_PROGRAMS_SEED, permission_registry.key().as_ref()], bump)]
+    pub approved_programs: Account<'info, ApprovedPrograms>,
+    #[account(init_if_needed, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = payer, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct AddBlock<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(init_if_needed, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = authority, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RemoveBlock<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct FreezeHolder<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::Pauser) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(init_if_needed, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, payer = authority, space = Permission::SPACE)]
+    pub permission: Account<'info, Permission>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct ThawHolder<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::Pauser) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(send_limits: TransferLimits, receive_limits: TransferLimits, owner: Pubkey)]
+pub struct SetTransferLimits<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, unlock_at: i64, owner: Pubkey)]
+pub struct GrantLockup<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Token account of the owner, checked in the instruction
+    pub token_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CheckTransfer<'info> {
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    /// CHECK: Token account of the mint, checked in the instruction
+    pub source: UncheckedAccount<'info>,
+    /// CHECK: Token account of the mint, checked in the instruction
+    pub destination: UncheckedAccount<'info>,
+    /// CHECK: Signer of the transfer, the owner of the source account or a delegate
+    pub authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateSnapshot<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::SuperAdmin) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RecordCheckpoint<'info> {
+    pub authority: Signer<'info>,
+    #[account(constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Token account of the owner, checked in the instruction
+    pub token_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct GetSnapshotBalance<'info> {
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Token account of the owner, checked in the instruction
+    pub token_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct GetUnlockedBalance<'info> {
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Token account of the owner, checked in the instruction
+    pub token_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RemovePermission<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut, constraint = permission_registry.has_role(&role_registry, &authority.key(), Role::PermissionManager) @ ErrorCode::MissingRole)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(seeds = [ROLE_REGISTRY_SEED, permission_registry.key().as_ref()], bump)]
+    pub role_registry: Account<'info, RoleRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, close = rent_recipient)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Any account can receive the rent
+    #[account(mut)]
+    pub rent_recipient: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct PruneExpired<'info> {
+    #[account(mut)]
+    pub cranker: Signer<'info>,
+    #[account(has_one = authority)]
+    pub permission_registry: Account<'info, PermissionRegistry>,
+    #[account(mut, seeds = [permission_registry.key().as_ref(), owner.as_ref()], bump, close = authority)]
+    pub permission: Account<'info, Permission>,
+    /// CHECK: Registry authority receiving the rest of the rent
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+}
+
+#[account]
+pub struct PermissionRegistry {
+    /// Super admin that cannot be revoked
+    pub authority: Pubkey,
+    /// Authority proposed with `propose_authority`, not effective until it accepts
+    pub pending_authority: Option<Pubkey>,
+    /// Mint whose transfers are governed by the registry
+    pub mint: Pubkey,
+    /// Seconds during which a permission is still accepted after its `expire_at`
+    pub grace_period: i64,
+    /// Seconds during which received tokens cannot be sent
+    pub lockup_period: i64,
+    pub mode: RegistryMode,
+    /// Every transfer is rejected while paused
+    pub paused: bool,
+    /// Transfers have to be signed by the owner of the source account, not by a delegate
+    pub owner_signed_only: bool,
+    /// Root of the Merkle tree of permissions holders can prove with `prove_permission`
+    pub merkle_root: Option<[u8; 32]>,
+    /// Whether the transfer policy has rules, the hook only loads it then
+    pub has_transfer_rules: bool,
+    /// Whether the hook maintains the holder index, see `set_holder_tracking`
+    pub track_holders: bool,
+    /// Number of holders with a positive balance in the index
+    pub holder_count: u64,
+    /// Transfers making a new holder are rejected once reached
+    pub max_holders: Option<u64>,
+    /// Last snapshot opened with `create_snapshot`, zero before the first one
+    pub snapshot_id: u64,
+    /// Holding a token of this mint stands for a permission, see `set_credential_mint`
+    pub credential_mint: Option<Pubkey>,
+    /// Mint authority the credential mint has to keep for its credentials to be accepted
+    pub kyc_provider: Option<Pubkey>,
+}
+
+impl PermissionRegistry {
+    const SPACE: usize = 8 + 32 + 33 + 32 + 8 + 8 + 1 + 1 + 1 + 33 + 1 + 1 + 8 + 9 + 8 + 33 + 33;
+
+    /// Whether `member` holds `role` in `role_registry`, the authority and super admins hold every
+    /// role
+    pub fn has_role(&self, role_registry: &RoleRegistry, member: &Pubkey, role: Role) -> bool {
+        *member == self.authority
+            || role_registry.roles.iter().any(|assignment| {
+                assignment.member == *member
+                    && (assignment.role == role || assignment.role == Role::SuperAdmin)
+            })
+    }
+
+    /// A permission proven against a previous Merkle root, or given to a PDA of a program that is
+    /// not in `approved_programs`, is no longer valid
+    fn is_current(&self, permission: &Permission, approved_programs: &[Pubkey]) -> bool {
+        (permission.merkle_root.is_none() || permission.merkle_root == self.merkle_root)
+            && permission
+                .program_owner
+                .map_or(true, |program_id| approved_programs.contains(&program_id))
+    }
+
+    fn is_expired(&self, permission: &Permission, now: i64) -> bool {
+        now >= permission.expire_at.saturating_add(self.grace_period)
+    }
+
+    /// Validate that the sender can send `amount` and record it in its send volume. In allowlist
+    /// mode a holder without a permission account has no permission unless it holds a credential
+    /// token, a permission account takes precedence over the credential. In denylist mode only
+    /// blocked holders are rejected. What is left in the source account after the transfer has to
+    /// cover the tokens locked in it
+    fn validate_send(
+        &self,
+        sender_permission: Option<&mut Permission>,
+        has_credential: bool,
+        approved_programs: &[Pubkey],
+        transfer: &processor::Transfer,
+        now: i64,
+    ) -> Result<()> {
+        let sender_permission = match (self.mode, sender_permission) {
+            (_, Some(permission)) if permission.frozen => return err!(ErrorCode::HolderFrozen),
+            (_, Some(permission)) if permission.blocked => {
+                return err!(ErrorCode::SenderBlocked);
+            }
+            (RegistryMode::Denylist, None) => return Ok(()),
+            (RegistryMode::Denylist, Some(permission)) => permission,
+            // A credential stands for a missing permission account
+            (RegistryMode::Allowlist, None) if has_credential => return Ok(()),
+            (RegistryMode::Allowlist, permission) => {
+                let permission = permission
+                    .filter(|permission| {
+                        permission.allowed_send && self.is_current(permission, approved_programs)
+                    })
+                    .ok_or(ErrorCode::MissingPermissionForSender)?;
+                require!(
+                    !self.is_expired(permission, now),
+                    ErrorCode::PermissionExpiredForSender
+                );
+                permission
+            }
+        };
+        require!(
+            sender_permission.send_limits.record(
+                &mut sender_permission.send_volume,
+                transfer.amount,
+                now
+            ),
+            ErrorCode::SenderTransferLimitExceeded
+        );
+        require_gte!(
+            transfer.source_balance,
+            sender_permission.locked_amount(&transfer.source, now),
+            ErrorCode::TokensLocked
+        );
+
+        Ok(())
+    }
+
+    /// Validate that the receiver can receive `amount`, record it in its receive volume and lock
+    /// it in the destination account for the lockup period, same rules as `validate_send`. The lot
+    /// is locked in the permission account, so while there is a lockup period a receiver without
+    /// one is rejected whatever the mode
+    fn validate_receive(
+        &self,
+        receiver_permission: Option<&mut Permission>,
+        has_credential: bool,
+        approved_programs: &[Pubkey],
+        transfer: &processor::Transfer,
+        now: i64,
+    ) -> Result<()> {
+        let receiver_permission = match (self.mode, receiver_permission) {
+            (_, Some(permission)) if permission.frozen => return err!(ErrorCode::HolderFrozen),
+            (_, Some(permission)) if permission.blocked => {
+                return err!(ErrorCode::ReceiverBlocked);
+            }
+            (RegistryMode::Denylist, None) => return self.validate_unlocked_receive(),
+            (RegistryMode::Denylist, Some(permission)) => permission,
+            (RegistryMode::Allowlist, None) if has_credential => {
+                return self.validate_unlocked_receive();
+            }
+            (RegistryMode::Allowlist, permission) => {
+                let permission = permission
+                    .filter(|permission| {
+                        permission.allowed_receive && self.is_current(permission, approved_programs)
+                    })
+                    .ok_or(ErrorCode::MissingPermissionForReceiver)?;
+                require!(
+                    !self.is_expired(permission, now),
+                    ErrorCode::PermissionExpiredForReceiver
+                );
+                permission
+            }
+        };
+        require!(
+            receiver_permission.receive_limits.record(
+                &mut receiver_permission.receive_volume,
+                transfer.amount,
+                now
+            ),
+            ErrorCode::ReceiverTransferLimitExceeded
+        );
+        if self.lockup_period > 0 {
+            receiver_permission.lock(
+                transfer.destination,
+                transfer.amount,
+                now.saturating_add(self.lockup_period),
+                now,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_unlocked_receive(&self) -> Result<()> {
+        require!(
+            self.lockup_period == 0,
+            ErrorCode::MissingPermissionForLockup
+        );
+        Ok(())
+    }
+
+    /// Validate that the authority of a transfer signed by someone else than the sender can move
+    /// the tokens of other holders, same rules as `validate_send`
+    fn validate_delegate(
+        &self,
+        authority_permission: Option<&Permission>,
+        approved_programs: &[Pubkey],
+        now: i64,
+    ) -> Result<()> {
+        require!(!self.owner_signed_only, ErrorCode::TransferNotSignedByOwner);
+        match (self.mode, authority_permission) {
+            (_, Some(permission)) if permission.frozen => err!(ErrorCode::HolderFrozen),
+            (_, Some(permission)) if permission.blocked => err!(ErrorCode::DelegateBlocked),
+            (RegistryMode::Denylist, _) => Ok(()),
+            (RegistryMode::Allowlist, permission) => {
+                let permission = permission
+                    .filter(|permission| {
+                        permission.allowed_delegate
+                            && self.is_current(permission, approved_programs)
+                    })
+                    .ok_or(ErrorCode::MissingPermissionForDelegate)?;
+                require!(
+                    !self.is_expired(permission, now),
+                    ErrorCode::PermissionExpiredForDelegate
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Record `balance` as the balance of `token_account` at the current snapshot, unless it was
+    /// already recorded since the snapshot was created
+    fn checkpoint_holder(
+        &self,
+        permission: Option<&mut Permission>,
+        token_account: Pubkey,
+        balance: u64,
+    ) {
+        if let (Some(permission), true) = (permission, self.snapshot_id > 0) {
+            permission.record_checkpoint(self.snapshot_id, token_account, balance);
+        }
+    }
+
+    /// Update the holder index entry of `permission` with the `amount` it sent or received, the
+    /// balance of a holder spans all of its token accounts. A `receiving` holder has to have a
+    /// permission account to be counted, whatever the registry mode
+    fn index_holder(
+        &mut self,
+        permission: Option<&mut Permission>,
+        amount: u64,
+        now: i64,
+        receiving: bool,
+    ) -> Result<()> {
+        let permission = match permission {
+            _ if !self.track_holders => return Ok(()),
+            Some(permission) => permission,
+            None if receiving => return err!(ErrorCode::HolderNotIndexed),
+            None => return Ok(()),
+        };
+        let balance = if receiving {
+            permission.balance.saturating_add(amount)
+        } else {
+            permission.balance.saturating_sub(amount)
+        };
+        self.set_holder_balance(permission, balance, now, receiving)
+    }
 
-        if !allowed_send {
-            return Err(ErrorCode::MissingPermissionForSender.into());
+    /// Set the balance of the holder index entry of `permission`, the holder count follows
+    /// holders going from and to an empty balance. Only a `receiving` holder can be rejected by
+    /// the maximum holder count, so that existing holders which are not indexed yet can always be
+    /// synced
+    fn set_holder_balance(
+        &mut self,
+        permission: &mut Permission,
+        balance: u64,
+        now: i64,
+        receiving: bool,
+    ) -> Result<()> {
+        match (permission.balance > 0, balance > 0) {
+            (false, true) => {
+                if let (true, Some(max_holders)) = (receiving, self.max_holders) {
+                    require_gt!(max_holders, self.holder_count, ErrorCode::MaxHoldersReached);
+                }
+                self.holder_count += 1;
+                permission.first_acquired_at = now;
+            }
+            (true, false) => self.holder_count = self.holder_count.saturating_sub(1),
+            _ => {}
         }
-        if !allowed_receive {
-            return Err(ErrorCode::MissingPermissionForReceiver.into());
+        permission.balance = balance;
+
+        Ok(())
+    }
+
+    /// Validate the transfer between two permitted holders against their counterparties and the
+    /// rules of the transfer policy
+    fn validate_transfer(
+        &self,
+        transfer_rules: &[TransferRule],
+        sender: &Pubkey,
+        sender_permission: Option<&Permission>,
+        receiver: &Pubkey,
+        receiver_permission: Option<&Permission>,
+    ) -> Result<()> {
+        require!(
+            sender_permission.map_or(true, |permission| permission.allows_counterparty(receiver)),
+            ErrorCode::CounterpartyNotAllowedForSender
+        );
+        require!(
+            receiver_permission.map_or(true, |permission| permission.allows_counterparty(sender)),
+            ErrorCode::CounterpartyNotAllowedForReceiver
+        );
+
+        if transfer_rules.is_empty() {
+            return Ok(());
         }
 
+        let sender_attributes = sender_permission
+            .map(|permission| permission.attributes)
+            .unwrap_or_default();
+        let receiver_attributes = receiver_permission
+            .map(|permission| permission.attributes)
+            .unwrap_or_default();
+        require!(
+            transfer_rules.iter().any(|rule| {
+                rule.sender.matches(&sender_attributes)
+                    && rule.receiver.matches(&receiver_attributes)
+            }),
+            ErrorCode::TransferNotAllowedByPolicy
+        );
+
         Ok(())
     }
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryMode {
+    /// Only holders with a permission can transfer
+    Allowlist,
+    /// Everyone can transfer except blocked holders
+    Denylist,
+}
+
+/// Allows transfers from holders matching `sender` to holders matching `receiver`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferRule {
+    pub sender: AttributeFilter,
+    pub receiver: AttributeFilter,
+}
+
+impl TransferRule {
+    const SPACE: usize = 2 * AttributeFilter::SPACE;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttributeFilter {
+    pub jurisdiction: JurisdictionFilter,
+    /// Any class matches when `None`
+    pub investor_class: Option<u8>,
+    pub min_accreditation_level: u8,
+}
+
+impl AttributeFilter {
+    const SPACE: usize = JurisdictionFilter::SPACE + 2 + 1;
+
+    fn matches(&self, attributes: &HolderAttributes) -> bool {
+        self.jurisdiction.matches(&attributes.jurisdiction)
+            && self.investor_class.map_or(true, |investor_class| {
+                investor_class == attributes.investor_class
+            })
+            && attributes.accreditation_level >= self.min_accreditation_level
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JurisdictionFilter {
+    Any,
+    Is([u8; 2]),
+    IsNot([u8; 2]),
+}
+
+impl JurisdictionFilter {
+    const SPACE: usize = 1 + 2;
+
+    fn matches(&self, jurisdiction: &[u8; 2]) -> bool {
+        match self {
+            JurisdictionFilter::Any => true,
+            JurisdictionFilter::Is(expected) => expected == jurisdiction,
+            JurisdictionFilter::IsNot(excluded) => excluded != jurisdiction,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Manages roles and the registry configuration
+    SuperAdmin,
+    /// Adds, updates and removes permissions
+    PermissionManager,
+    /// Halts transfers during incidents
+    Pauser,
+    /// Read-only access for compliance reviews
+    Auditor,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoleAssignment {
+    pub member: Pubkey,
+    pub role: Role,
+}
+
+impl RoleAssignment {
+    const SPACE: usize = 32 + 1;
+}
+
+/// Roles given to other keys than the authority, stored at the PDA
+/// `[ROLE_REGISTRY_SEED, permission_registry]` out of the registry the transfer hook decodes
+#[account]
+pub struct RoleRegistry {
+    pub roles: Vec<RoleAssignment>,
+}
+
+impl RoleRegistry {
+    fn space(roles: usize) -> usize {
+        8 + 4 + roles * RoleAssignment::SPACE
+    }
+}
+
+/// A transfer has to match one of the rules, unless there are none. Stored at the PDA
+/// `[TRANSFER_POLICY_SEED, permission_registry]`, the hook only loads it when there are rules
+#[account]
+pub struct TransferPolicy {
+    pub transfer_rules: Vec<TransferRule>,
+}
+
+impl TransferPolicy {
+    fn space(transfer_rules: usize) -> usize {
+        8 + 4 + transfer_rules * TransferRule::SPACE
+    }
+}
+
+/// Programs whose PDAs can hold tokens, see `approve_program_holder`. Stored at the PDA
+/// `[APPROVED_PROGRAMS_SEED, permission_registry]`, the hook only loads it when it is among the
+/// extra accounts
+#[account]
+pub struct ApprovedPrograms {
+    pub programs: Vec<Pubkey>,
+}
+
+impl ApprovedPrograms {
+    fn space(programs: usize) -> usize {
+        8 + 4 + programs * 32
+    }
+}
+
+/// Permission of a single owner, stored at the PDA `[permission_registry, owner]`
+#[account]
 pub struct Permission {
     pub owner: Pubkey,
     pub allowed_send: bool,
     pub allowed_receive: bool,
+    /// Can transfer the tokens of other holders as a delegate or permanent delegate
+    pub allowed_delegate: bool,
     pub expire_at: i64,
+    /// Rejected in both registry modes, see `add_block`
+    pub blocked: bool,
+    /// Transfers are halted, see `freeze_holder`
+    pub frozen: bool,
+    /// Root the permission was proven against, `None` when added by a permission manager
+    pub merkle_root: Option<[u8; 32]>,
+    /// Program the owner is a PDA of, see `approve_program_holder`
+    pub program_owner: Option<Pubkey>,
+    /// Only holders the owner can transfer with, anyone when empty. At most `MAX_COUNTERPARTIES`
+    pub counterparties: Vec<Pubkey>,
+    pub attributes: HolderAttributes,
+    pub send_limits: TransferLimits,
+    pub receive_limits: TransferLimits,
+    pub send_volume: TransferVolume,
+    pub receive_volume: TransferVolume,
+    /// Tokens received during the lockup period of the registry or locked with `grant_lockup`
+    pub locked_lots: Vec<LockedLot>,
+    /// Total balance of the token accounts of the holder, as of its last indexed transfer or sync
+    pub balance: u64,
+    /// When the holder last went from an empty balance to holding tokens
+    pub first_acquired_at: i64,
+    /// Balances of the token accounts before their first transfer following each snapshot, by
+    /// increasing snapshot ID
+    pub checkpoints: Vec<Checkpoint>,
+    /// Latest snapshot of the checkpoints dropped to make room, balances at or before it are lost
+    pub dropped_snapshot_id: u64,
 }
 
 impl Permission {
-    const SPACE: usize = 32 + 2 + 8;
+    const SPACE: usize = 8
+        + 32
+        + 3
+        + 8
+        + 2
+        + 33
+        + 33
+        + 4
+        + MAX_COUNTERPARTIES * 32
+        + HolderAttributes::SPACE
+        + 2 * TransferLimits::SPACE
+        + 2 * TransferVolume::SPACE
+        + 4
+        + MAX_LOCKED_LOTS * LockedLot::SPACE
+        + 8
+        + 8
+        + 4
+        + MAX_CHECKPOINTS * Checkpoint::SPACE
+        + 8;
+
+    fn new(owner: Pubkey, allowed_send: bool, allowed_receive: bool, expire_at: i64) -> Self {
+        Permission {
+            owner,
+            allowed_send,
+            allowed_receive,
+            allowed_delegate: false,
+            expire_at,
+            blocked: false,
+            frozen: false,
+            merkle_root: None,
+            program_owner: None,
+            counterparties: vec![],
+            attributes: HolderAttributes::default(),
+            send_limits: TransferLimits::default(),
+            receive_limits: TransferLimits::default(),
+            send_volume: TransferVolume::default(),
+            receive_volume: TransferVolume::default(),
+            locked_lots: vec![],
+            balance: 0,
+            first_acquired_at: 0,
+            checkpoints: vec![],
+            dropped_snapshot_id: 0,
+        }
+    }
+
+    /// Whether the account was just created by `init_if_needed`, its data is still zeroed
+    fn is_new(&self) -> bool {
+        self.owner == Pubkey::default()
+    }
+
+    fn allows_counterparty(&self, counterparty: &Pubkey) -> bool {
+        self.counterparties.is_empty() || self.counterparties.contains(counterparty)
+    }
+
+    /// Sum of the lots of `token_account` still locked at `now`
+    pub fn locked_amount(&self, token_account: &Pubkey, now: i64) -> u64 {
+        self.locked_lots
+            .iter()
+            .filter(|lot| lot.token_account == *token_account && lot.unlock_at > now)
+            .fold(0, |locked, lot| locked.saturating_add(lot.amount))
+    }
+
+    /// Lock `amount` in `token_account` until `unlock_at` and drop the unlocked lots. Once there
+    /// are `MAX_LOCKED_LOTS`, the lot is merged into the last one of the same account which is
+    /// locked until the later of both, erring on the side of locking tokens longer. Lots cannot be
+    /// merged across accounts, so the lock fails when the account has none
+    fn lock(&mut self, token_account: Pubkey, amount: u64, unlock_at: i64, now: i64) -> Result<()> {
+        self.locked_lots.retain(|lot| lot.unlock_at > now);
+        if unlock_at <= now {
+            return Ok(());
+        }
+        if self.locked_lots.len() < MAX_LOCKED_LOTS {
+            self.locked_lots.push(LockedLot {
+                token_account,
+                amount,
+                unlock_at,
+            });
+            return Ok(());
+        }
+        let last = self
+            .locked_lots
+            .iter_mut()
+            .rev()
+            .find(|lot| lot.token_account == token_account)
+            .ok_or(ErrorCode::TooManyLockedLots)?;
+        last.amount = last.amount.saturating_add(amount);
+        last.unlock_at = last.unlock_at.max(unlock_at);
+        Ok(())
+    }
+
+    /// Record the balance of `token_account` at `snapshot_id` if it is the first time the account
+    /// moves since the snapshot was created, in which case its balance has not changed since
+    fn record_checkpoint(&mut self, snapshot_id: u64, token_account: Pubkey, balance: u64) {
+        if self.checkpoints.iter().any(|checkpoint| {
+            checkpoint.token_account == token_account && checkpoint.snapshot_id >= snapshot_id
+        }) {
+            return;
+        }
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.dropped_snapshot_id = self.checkpoints.remove(0).snapshot_id;
+        }
+        self.checkpoints.push(Checkpoint {
+            token_account,
+            snapshot_id,
+            balance,
+        });
+    }
+
+    /// Balance of `token_account` at `snapshot_id`, the balance recorded at its first checkpoint
+    /// following it since the account did not move in between. `None` when the account has not
+    /// moved since
+    fn snapshot_balance(&self, snapshot_id: u64, token_account: &Pubkey) -> Result<Option<u64>> {
+        require_gt!(
+            snapshot_id,
+            self.dropped_snapshot_id,
+            ErrorCode::SnapshotBalanceUnavailable
+        );
+        Ok(self
+            .checkpoints
+            .iter()
+            .find(|checkpoint| {
+                checkpoint.token_account == *token_account && checkpoint.snapshot_id >= snapshot_id
+            })
+            .map(|checkpoint| checkpoint.balance))
+    }
+
+    /// Whether closing the permission loses nothing but its flags: blocks, freezes, restrictions
+    /// and limits would be lifted for holders let through by the registry mode or a credential,
+    /// and locked tokens, index entries and checkpoints would be lost
+    fn is_prunable(&self, now: i64) -> bool {
+        !self.blocked
+            && !self.frozen
+            && self.counterparties.is_empty()
+            && self.attributes == HolderAttributes::default()
+            && self.send_limits == TransferLimits::default()
+            && self.receive_limits == TransferLimits::default()
+            && self.locked_lots.iter().all(|lot| lot.unlock_at <= now)
+            && self.balance == 0
+            && self.checkpoints.is_empty()
+    }
+
+    /// Whether the hook has to write back the permission after a transfer
+    fn needs_write_back(&self) -> bool {
+        self.send_limits.tracks_volume()
+            || self.receive_limits.tracks_volume()
+            || !self.locked_lots.is_empty()
+            || !self.checkpoints.is_empty()
+    }
+}
+
+/// Balance of a token account of a holder at a snapshot
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub token_account: Pubkey,
+    pub snapshot_id: u64,
+    pub balance: u64,
+}
+
+impl Checkpoint {
+    const SPACE: usize = 32 + 8 + 8;
+}
+
+/// Tokens received by a holder in a token account which cannot be sent before `unlock_at`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockedLot {
+    pub token_account: Pubkey,
+    pub amount: u64,
+    pub unlock_at: i64,
+}
+
+impl LockedLot {
+    const SPACE: usize = 32 + 8 + 8;
+}
+
+/// Attributes matched by the transfer rules of the registry
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HolderAttributes {
+    /// ISO 3166-1 alpha-2 country code, e.g. `*b"US"`
+    pub jurisdiction: [u8; 2],
+    pub investor_class: u8,
+    pub accreditation_level: u8,
+}
+
+impl HolderAttributes {
+    const SPACE: usize = 2 + 1 + 1;
+}
+
+/// Caps on the amounts a holder can transfer, `None` is unlimited
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferLimits {
+    /// Maximum amount of a single transfer
+    pub max_amount: Option<u64>,
+    /// Maximum volume over 24 hours
+    pub daily_volume: Option<u64>,
+    /// Maximum volume over 30 days
+    pub monthly_volume: Option<u64>,
+}
+
+impl TransferLimits {
+    const SPACE: usize = 3 * (1 + 8);
+
+    fn tracks_volume(&self) -> bool {
+        self.daily_volume.is_some() || self.monthly_volume.is_some()
+    }
+
+    /// Record `amount` in `volume`, false if it goes over the limits
+    fn record(&self, volume: &mut TransferVolume, amount: u64, now: i64) -> bool {
+        let within = |limit: Option<u64>, total: u64| limit.map_or(true, |limit| total <= limit);
+        let (daily, monthly) = volume.record(amount, now);
+        within(self.max_amount, amount)
+            && within(self.daily_volume, daily)
+            && within(self.monthly_volume, monthly)
+    }
+}
+
+/// Volumes checked against the `TransferLimits`, over rolling windows split into buckets
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferVolume {
+    /// Amounts transferred in each hour of the last 24 hours
+    pub daily: [u64; 24],
+    /// Amounts transferred in each day of the last 30 days
+    pub monthly: [u64; 30],
+    /// Time of the last recorded transfer, the buckets elapsed since are cleared on the next one
+    pub last_recorded_at: i64,
+}
+
+impl TransferVolume {
+    const SPACE: usize = 24 * 8 + 30 * 8 + 8;
+
+    /// Add `amount` to the volumes and return the totals of the last 24 hours, to the hour, and
+    /// of the last 30 days, to the day
+    fn record(&mut self, amount: u64, now: i64) -> (u64, u64) {
+        let last_recorded_at = self.last_recorded_at;
+        self.last_recorded_at = now;
+        (
+            Self::record_window(&mut self.daily, VOLUME_DAY, amount, last_recorded_at, now),
+            Self::record_window(
+                &mut self.monthly,
+                VOLUME_MONTH,
+                amount,
+                last_recorded_at,
+                now,
+            ),
+        )
+    }
+
+    /// Add `amount` to the bucket of `now` in a window of `duration` seconds and return the total
+    /// of the window. Buckets are indexed by their period modulo the bucket count, the ones which
+    /// elapsed since `last_recorded_at` are cleared first
+    fn record_window(
+        buckets: &mut [u64],
+        duration: i64,
+        amount: u64,
+        last_recorded_at: i64,
+        now: i64,
+    ) -> u64 {
+        let count = buckets.len() as i64;
+        let last_period = last_recorded_at.div_euclid(duration / count);
+        let period = now.div_euclid(duration / count);
+        if period.saturating_sub(last_period) >= count {
+            buckets.fill(0);
+        } else {
+            for elapsed in last_period + 1..=period {
+                buckets[elapsed.rem_euclid(count) as usize] = 0;
+            }
+        }
+
+        let bucket = &mut buckets[period.rem_euclid(count) as usize];
+        *bucket = bucket.saturating_add(amount);
+        buckets
+            .iter()
+            .fold(0, |total, amount| total.saturating_add(*amount))
+    }
+}
+
+/// Returned by `check_transfer`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferVerdict {
+    /// Whether the transfer hook would accept the transfer
+    pub allowed: bool,
+    /// Error the transfer hook would fail with, as a `ProgramError` converted to `u64`. Custom
+    /// errors, including the `ErrorCode` of the program, are their code
+    pub error_code: Option<u64>,
+    /// Earliest expiry of the permissions of the sender, the receiver and the authority among the
+    /// remaining accounts, `None` when there are none
+    pub expire_at: Option<i64>,
+}
+
+/// Permission asked for by a holder, stored at the PDA
+/// `[PERMISSION_REQUEST_SEED, permission_registry, owner]` until approved or rejected
+#[account]
+pub struct PermissionRequest {
+    pub owner: Pubkey,
+    pub allowed_send: bool,
+    pub allowed_receive: bool,
+    /// Hash of the off-chain KYC documents of the holder
+    pub reference_hash: Option<[u8; 32]>,
+    pub requested_at: i64,
+}
+
+impl PermissionRequest {
+    const SPACE: usize = 8 + 32 + 2 + 33 + 8;
+}
+
+#[event]
+pub struct PermissionAdded {
+    pub permission_registry: Pubkey,
+    pub owner: Pubkey,
+    pub allowed_send: bool,
+    pub allowed_receive: bool,
+    pub expire_at: i64,
+}
+
+#[event]
+pub struct PermissionUpdated {
+    pub permission_registry: Pubkey,
+    pub owner: Pubkey,
+    pub allowed_send: bool,
+    pub allowed_receive: bool,
+    pub expire_at: i64,
+}
+
+#[event]
+pub struct PermissionRequested {
+    pub permission_registry: Pubkey,
+    pub owner: Pubkey,
+    pub allowed_send: bool,
+    pub allowed_receive: bool,
+    pub reference_hash: Option<[u8; 32]>,
+}
+
+#[event]
+pub struct PermissionRequestRejected {
+    pub permission_registry: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct PermissionRemoved {
+    pub permission_registry: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct SnapshotCreated {
+    pub permission_registry: Pubkey,
+    pub snapshot_id: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted by the transfer hook once a transfer passed every check
+#[event]
+pub struct TransferValidated {
+    pub mint: Pubkey,
+    pub sender: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
 }
 
 #[error_code]
@@ -162,4 +2300,55 @@ pub enum ErrorCode {
     MissingPermission,
     MissingPermissionForSender,
     MissingPermissionForReceiver,
+    PermissionExpiredForSender,
+    PermissionExpiredForReceiver,
+    InvalidGracePeriod,
+    PermissionNotExpired,
+    IncorrectMintAuthority,
+    SenderTransferLimitExceeded,
+    ReceiverTransferLimitExceeded,
+    PermissionNotWritable,
+    MissingRole,
+    RoleAlreadyGranted,
+    RoleNotGranted,
+    NoPendingAuthority,
+    IncorrectPendingAuthority,
+    MerkleRootNotSet,
+    InvalidMerkleProof,
+    TransferNotAllowedByPolicy,
+    SenderBlocked,
+    ReceiverBlocked,
+    TransfersPaused,
+    HolderFrozen,
+    IncorrectTokenAccount,
+    ProgramCalledOutsideOfTransfer,
+    TransferNotSignedByOwner,
+    MissingPermissionForDelegate,
+    PermissionExpiredForDelegate,
+    DelegateBlocked,
+    ProgramNotApproved,
+    InvalidProgramHolderSeeds,
+    TooManyCounterparties,
+    CounterpartyNotAllowedForSender,
+    CounterpartyNotAllowedForReceiver,
+    InvalidLockupPeriod,
+    TokensLocked,
+    MaxHoldersReached,
+    PermissionRegistryNotWritable,
+    InvalidSnapshotId,
+    SnapshotBalanceUnavailable,
+    InvalidCredentialMint,
+    PermissionNotPrunable,
+    HolderNotIndexed,
+    PermissionAlreadyExists,
+    MissingPermissionForLockup,
+    MissingPermissionAccount,
+    MissingTransferPolicy,
+    TooManyLockedLots,
+}
+
+impl From<ErrorCode> for ProgramError {
+    fn from(error: ErrorCode) -> Self {
+        Error::from(error).into()
+    }
 }
@@ -0,0 +1,37 @@
+//! Merkle tree of permissions committed in the registry, proofs are built off-chain with the
+//! same hashing.
+
+use anchor_lang::solana_program::{hash::hashv, pubkey::Pubkey};
+
+// Leaves and nodes are hashed with different prefixes so that a node cannot pass for a leaf
+const LEAF_PREFIX: &[u8] = &[0];
+const NODE_PREFIX: &[u8] = &[1];
+
+/// Leaf committing to the permission of `owner`
+pub fn compute_leaf(
+    owner: &Pubkey,
+    allowed_send: bool,
+    allowed_receive: bool,
+    expire_at: i64,
+) -> [u8; 32] {
+    hashv(&[
+        LEAF_PREFIX,
+        owner.as_ref(),
+        &[u8::from(allowed_send), u8::from(allowed_receive)],
+        &expire_at.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+/// Parent of two nodes, they are sorted so proofs do not need to carry the side of each node
+pub fn compute_parent(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[NODE_PREFIX, left, right]).to_bytes()
+}
+
+pub fn verify_proof(root: &[u8; 32], leaf: [u8; 32], proof: &[[u8; 32]]) -> bool {
+    proof
+        .iter()
+        .fold(leaf, |node, sibling| compute_parent(&node, sibling))
+        == *root
+}
@@ -1,8 +1,12 @@
 //! Program state processor
 
-use anchor_lang::prelude::Account;
+use anchor_lang::{emit, AccountDeserialize, AccountSerialize};
 
-use crate::{inline_spl_token::get_account_owner, PermissionRegistry};
+use crate::{
+    get_approved_programs_address, get_permission_address, get_permission_registry_address,
+    get_transfer_policy_address, ApprovedPrograms, ErrorCode, Permission, PermissionRegistry,
+    TransferPolicy, TransferValidated,
+};
 
 use {
     crate::inline_spl_token,
@@ -12,33 +16,63 @@ use {
         msg,
         program::invoke_signed,
         program_error::ProgramError,
+        program_option::COption,
         pubkey::Pubkey,
         system_instruction,
+        sysvar::{clock::Clock, rent::Rent, Sysvar},
+    },
+    spl_tlv_account_resolution::{
+        account::ExtraAccountMeta, seeds::Seed, state::ExtraAccountMetaList,
     },
-    spl_tlv_account_resolution::state::ExtraAccountMetas,
     spl_transfer_hook_interface::{
         collect_extra_account_metas_signer_seeds,
         error::TransferHookError,
         get_extra_account_metas_address, get_extra_account_metas_address_and_bump_seed,
         instruction::{ExecuteInstruction, TransferHookInstruction},
     },
-    spl_type_length_value::state::TlvStateBorrowed,
 };
 
+/// Index of the permission registry in the accounts of `Execute`, it is the first extra account
+const PERMISSION_REGISTRY_INDEX: u8 = 5;
+/// Indexes of the source and destination token accounts and of the authority in the accounts of
+/// `Execute`
+const SOURCE_INDEX: u8 = 0;
+const DESTINATION_INDEX: u8 = 2;
+const AUTHORITY_INDEX: u8 = 3;
+/// Offset and length of the owner in the data of a token account
+const TOKEN_ACCOUNT_OWNER_OFFSET: u8 = 32;
+const TOKEN_ACCOUNT_OWNER_LENGTH: u8 = 32;
+
 /// Processes an [Execute](enum.TransferHookInstruction.html) instruction.
 pub fn process_execute(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    _amount: u64,
+    amount: u64,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
 
     let source_account_info = next_account_info(account_info_iter)?;
     let mint_info = next_account_info(account_info_iter)?;
     let destination_account_info = next_account_info(account_info_iter)?;
-    let _authority_info = next_account_info(account_info_iter)?;
+    let authority_info = next_account_info(account_info_iter)?;
     let extra_account_metas_info = next_account_info(account_info_iter)?;
 
+    // Only accept calls made by token-2022 in the middle of a transfer of the mint
+    for account_info in [source_account_info, mint_info, destination_account_info] {
+        if *account_info.owner != inline_spl_token::ID {
+            return Err(ErrorCode::IncorrectTokenAccount.into());
+        }
+    }
+    for account_info in [source_account_info, destination_account_info] {
+        let account_mint = inline_spl_token::get_account_mint(&account_info.try_borrow_data()?)?;
+        if account_mint != *mint_info.key {
+            return Err(ErrorCode::IncorrectTokenAccount.into());
+        }
+    }
+    if !inline_spl_token::is_account_transferring(&source_account_info.try_borrow_data()?)? {
+        return Err(ErrorCode::ProgramCalledOutsideOfTransfer.into());
+    }
+
     // For the example program, we just check that the correct pda and validation
     // pubkeys are provided
     let expected_validation_address = get_extra_account_metas_address(mint_info.key, program_id);
@@ -46,51 +80,426 @@ pub fn process_execute(
         return Err(ProgramError::InvalidSeeds);
     }
 
-    let data = extra_account_metas_info.try_borrow_data()?;
-    let state = TlvStateBorrowed::unpack(&data).unwrap();
-    let extra_account_metas =
-        ExtraAccountMetas::unpack_with_tlv_state::<ExecuteInstruction>(&state)?;
-
-    // if incorrect number of are provided, error
+    // The permission PDAs are resolved from the owners of the token accounts, so every extra
+    // account is checked against the accounts of this very transfer
+    ExtraAccountMetaList::check_account_infos::<ExecuteInstruction>(
+        accounts,
+        &TransferHookInstruction::Execute { amount }.pack(),
+        program_id,
+        &extra_account_metas_info.try_borrow_data()?,
+    )?;
     let extra_account_infos = account_info_iter.as_slice();
-    let account_metas = extra_account_metas.data();
-    if extra_account_infos.len() != account_metas.len() {
-        return Err(TransferHookError::IncorrectAccount.into());
-    }
 
-    // Let's assume that they're provided in the correct order
-    for (i, account_info) in extra_account_infos.iter().enumerate() {
-        if &account_metas[i] != account_info {
-            return Err(TransferHookError::IncorrectAccount.into());
+    // Custom validation work starts here
+
+    // The registry of the mint has to be part of the extra accounts
+    let permission_registry_address = get_permission_registry_address(mint_info.key);
+    let permission_registry_info = extra_account_infos
+        .iter()
+        .find(|account_info| *account_info.key == permission_registry_address)
+        .ok_or(TransferHookError::IncorrectAccount)?;
+    let mut permission_registry =
+        load_account::<PermissionRegistry>(program_id, permission_registry_info)?
+            .ok_or(ProgramError::UninitializedAccount)?;
+
+    let source_balance =
+        inline_spl_token::get_account_amount(&source_account_info.try_borrow_data()?)?;
+    let destination_balance =
+        inline_spl_token::get_account_amount(&destination_account_info.try_borrow_data()?)?;
+    let (source_balance_before, destination_balance_before) =
+        if source_account_info.key == destination_account_info.key {
+            (source_balance, destination_balance)
+        } else {
+            (
+                source_balance.saturating_add(amount),
+                destination_balance.saturating_sub(amount),
+            )
+        };
+    let transfer = Transfer {
+        source: *source_account_info.key,
+        destination: *destination_account_info.key,
+        sender: inline_spl_token::get_account_owner(&source_account_info.try_borrow_data()?)?,
+        receiver: inline_spl_token::get_account_owner(
+            &destination_account_info.try_borrow_data()?,
+        )?,
+        authority: *authority_info.key,
+        amount,
+        source_balance_before,
+        source_balance,
+        destination_balance_before,
+    };
+
+    let holder_count = permission_registry.holder_count;
+    check_transfer(
+        program_id,
+        permission_registry_info.key,
+        &mut permission_registry,
+        &transfer,
+        extra_account_infos,
+        true,
+    )?;
+    if permission_registry.holder_count != holder_count {
+        if !permission_registry_info.is_writable {
+            return Err(ErrorCode::PermissionRegistryNotWritable.into());
         }
+        let mut data = permission_registry_info.try_borrow_mut_data()?;
+        permission_registry.try_serialize(&mut &mut data[..])?;
     }
 
-    // Custom validation work starts here
+    emit!(TransferValidated {
+        mint: *mint_info.key,
+        sender: transfer.sender,
+        receiver: transfer.receiver,
+        amount,
+    });
+    Ok(())
+}
 
-    let permission_registry =
-        Account::<PermissionRegistry>::try_from(&extra_account_infos.first().unwrap())?;
+/// A transfer checked against a permission registry
+pub struct Transfer {
+    pub source: Pubkey,
+    pub destination: Pubkey,
+    /// Owner of the source account
+    pub sender: Pubkey,
+    /// Owner of the destination account
+    pub receiver: Pubkey,
+    /// Signer of the transfer, the sender or a delegate
+    pub authority: Pubkey,
+    pub amount: u64,
+    pub source_balance_before: u64,
+    pub source_balance: u64,
+    pub destination_balance_before: u64,
+}
 
-    let sender = inline_spl_token::get_account_owner(&source_account_info.try_borrow_data()?)?;
-    let receiver =
-        inline_spl_token::get_account_owner(&destination_account_info.try_borrow_data()?)?;
+/// Runs every check of the transfer hook on `transfer`, updating `permission_registry` and the
+/// permissions found in `account_infos`. The permissions are only written back with `write_back`,
+/// otherwise the checks have no effect
+pub fn check_transfer(
+    program_id: &Pubkey,
+    permission_registry_address: &Pubkey,
+    permission_registry: &mut PermissionRegistry,
+    transfer: &Transfer,
+    account_infos: &[AccountInfo],
+    write_back: bool,
+) -> Result<(), ProgramError> {
+    if permission_registry.paused {
+        return Err(ErrorCode::TransfersPaused.into());
+    }
 
-    permission_registry.validate_transfer(&sender, &receiver)?;
+    // Only the sender and receiver permissions are looked up, their PDAs have to be among the
+    // accounts, created or not, so that a block, freeze or limit cannot be skipped by leaving them
+    // out. The sender permission is written back before the receiver one is loaded since they are
+    // the same account for a transfer to oneself
+    let clock = Clock::get()?;
+    let approved_programs =
+        load_approved_programs(program_id, permission_registry_address, account_infos)?;
+    let track_holders = permission_registry.track_holders;
+    let sender_credential = holds_credential(permission_registry, &transfer.sender, account_infos)?;
+    let receiver_credential =
+        holds_credential(permission_registry, &transfer.receiver, account_infos)?;
+    let sender_permission = validate_holder(
+        program_id,
+        permission_registry_address,
+        &transfer.sender,
+        account_infos,
+        write_back,
+        track_holders,
+        |mut permission| {
+            permission_registry.validate_send(
+                permission.as_deref_mut(),
+                sender_credential,
+                &approved_programs,
+                transfer,
+                clock.unix_timestamp,
+            )?;
+            permission_registry.checkpoint_holder(
+                permission.as_deref_mut(),
+                transfer.source,
+                transfer.source_balance_before,
+            );
+            permission_registry.index_holder(
+                permission,
+                transfer.amount,
+                clock.unix_timestamp,
+                false,
+            )
+        },
+    )?;
+    let receiver_permission = validate_holder(
+        program_id,
+        permission_registry_address,
+        &transfer.receiver,
+        account_infos,
+        write_back,
+        track_holders,
+        |mut permission| {
+            permission_registry.validate_receive(
+                permission.as_deref_mut(),
+                receiver_credential,
+                &approved_programs,
+                transfer,
+                clock.unix_timestamp,
+            )?;
+            permission_registry.checkpoint_holder(
+                permission.as_deref_mut(),
+                transfer.destination,
+                transfer.destination_balance_before,
+            );
+            permission_registry.index_holder(
+                permission,
+                transfer.amount,
+                clock.unix_timestamp,
+                true,
+            )
+        },
+    )?;
+
+    // A transfer signed by a delegate or permanent delegate needs the authority permission PDA
+    // among the accounts too
+    if transfer.authority != transfer.sender {
+        let authority_permission_info = find_permission_info(
+            permission_registry_address,
+            &transfer.authority,
+            account_infos,
+        )
+        .ok_or(ErrorCode::MissingPermissionAccount)?;
+        let authority_permission = load_permission(program_id, Some(authority_permission_info))?;
+        permission_registry.validate_delegate(
+            authority_permission.as_deref(),
+            &approved_programs,
+            clock.unix_timestamp,
+        )?;
+    }
+
+    // The rules are only loaded when there are some, they have to be part of the extra accounts
+    let transfer_rules = if permission_registry.has_transfer_rules {
+        let transfer_policy_address = get_transfer_policy_address(permission_registry_address);
+        let transfer_policy_info = account_infos
+            .iter()
+            .find(|account_info| *account_info.key == transfer_policy_address)
+            .ok_or(ErrorCode::MissingTransferPolicy)?;
+        load_account::<TransferPolicy>(program_id, transfer_policy_info)?
+            .ok_or(ErrorCode::MissingTransferPolicy)?
+            .transfer_rules
+    } else {
+        vec![]
+    };
+    permission_registry.validate_transfer(
+        &transfer_rules,
+        &transfer.sender,
+        sender_permission.as_deref(),
+        &transfer.receiver,
+        receiver_permission.as_deref(),
+    )?;
 
     Ok(())
 }
 
-/// Processes a [InitializeExtraAccountMetas](enum.TransferHookInstruction.html) instruction.
-pub fn process_initialize_extra_account_metas(
+/// Validates the permission of `owner` with `validate` and returns it for the checks involving
+/// both holders. The permission PDA has to be among `account_infos` even if it was never created.
+/// With `write_back`, the permission is written back when it tracks transfer volumes, locked lots
+/// or holders
+fn validate_holder(
     program_id: &Pubkey,
-    accounts: &[AccountInfo],
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    account_infos: &[AccountInfo],
+    write_back: bool,
+    track_holders: bool,
+    validate: impl FnOnce(Option<&mut Permission>) -> anchor_lang::Result<()>,
+) -> Result<Option<Box<Permission>>, ProgramError> {
+    let permission_info = find_permission_info(permission_registry, owner, account_infos)
+        .ok_or(ErrorCode::MissingPermissionAccount)?;
+    let mut permission = load_permission(program_id, Some(permission_info))?;
 
-    let extra_account_metas_info = next_account_info(account_info_iter)?;
-    let mint_info = next_account_info(account_info_iter)?;
-    let authority_info = next_account_info(account_info_iter)?;
-    let _system_program_info = next_account_info(account_info_iter)?;
+    validate(permission.as_deref_mut())?;
+
+    if let Some(permission) = &permission {
+        if write_back && (track_holders || permission.needs_write_back()) {
+            if !permission_info.is_writable {
+                return Err(ErrorCode::PermissionNotWritable.into());
+            }
+            let mut data = permission_info.try_borrow_mut_data()?;
+            permission.try_serialize(&mut &mut data[..])?;
+        }
+    }
+
+    Ok(permission)
+}
+
+/// Earliest `expire_at` of the permissions of `owners` found in `account_infos`, `None` if there
+/// are none. Accounts which are not permissions are skipped
+pub fn earliest_expiry(
+    program_id: &Pubkey,
+    permission_registry: &Pubkey,
+    owners: &[Pubkey],
+    account_infos: &[AccountInfo],
+) -> Option<i64> {
+    owners
+        .iter()
+        .filter_map(|owner| {
+            let permission_info = find_permission_info(permission_registry, owner, account_infos);
+            load_permission(program_id, permission_info).ok().flatten()
+        })
+        .map(|permission| permission.expire_at)
+        .min()
+}
+
+/// Whether `owner` holds a token of the credential mint of `permission_registry` in one of
+/// `account_infos`, the credential mint and token accounts are passed as extra accounts. A
+/// credential frozen by its issuer is revoked, and none is accepted once the mint authority is no
+/// longer the KYC provider
+fn holds_credential(
+    permission_registry: &PermissionRegistry,
+    owner: &Pubkey,
+    account_infos: &[AccountInfo],
+) -> Result<bool, ProgramError> {
+    let (credential_mint, kyc_provider) = match (
+        permission_registry.credential_mint,
+        permission_registry.kyc_provider,
+    ) {
+        (Some(credential_mint), Some(kyc_provider)) => (credential_mint, kyc_provider),
+        _ => return Ok(false),
+    };
+    let credential_mint_info = match account_infos
+        .iter()
+        .find(|account_info| *account_info.key == credential_mint)
+    {
+        Some(account_info) if *account_info.owner == inline_spl_token::ID => account_info,
+        _ => return Ok(false),
+    };
+    if inline_spl_token::get_mint_authority(&credential_mint_info.try_borrow_data()?)?
+        != COption::Some(kyc_provider)
+    {
+        return Ok(false);
+    }
+
+    for account_info in account_infos {
+        if *account_info.owner != inline_spl_token::ID {
+            continue;
+        }
+        let data = account_info.try_borrow_data()?;
+        if inline_spl_token::is_token_account(&data)
+            && inline_spl_token::get_account_mint(&data)? == credential_mint
+            && inline_spl_token::get_account_owner(&data)? == *owner
+            && inline_spl_token::get_account_amount(&data)? > 0
+            && !inline_spl_token::is_account_frozen(&data)?
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Programs whose PDAs can hold tokens, empty unless their account is among `account_infos`
+fn load_approved_programs(
+    program_id: &Pubkey,
+    permission_registry: &Pubkey,
+    account_infos: &[AccountInfo],
+) -> Result<Vec<Pubkey>, ProgramError> {
+    let approved_programs_address = get_approved_programs_address(permission_registry);
+    match account_infos
+        .iter()
+        .find(|account_info| *account_info.key == approved_programs_address)
+    {
+        Some(account_info) => Ok(load_account::<ApprovedPrograms>(program_id, account_info)?
+            .map_or_else(Vec::new, |approved_programs| approved_programs.programs)),
+        None => Ok(vec![]),
+    }
+}
+
+/// Deserializes the permission found with `find_permission_info`, `None` if there is none. The
+/// permission is boxed to keep its volume buckets off the stack
+fn load_permission(
+    program_id: &Pubkey,
+    permission_info: Option<&AccountInfo>,
+) -> Result<Option<Box<Permission>>, ProgramError> {
+    match permission_info {
+        Some(permission_info) => {
+            Ok(load_account::<Permission>(program_id, permission_info)?.map(Box::new))
+        }
+        None => Ok(None),
+    }
+}
 
+/// Deserializes an account owned by the program, `None` if the account has not been created
+fn load_account<T: AccountDeserialize>(
+    program_id: &Pubkey,
+    account_info: &AccountInfo,
+) -> Result<Option<T>, ProgramError> {
+    if account_info.owner != program_id {
+        return Ok(None);
+    }
+    let data = account_info.try_borrow_data()?;
+    let account = T::try_deserialize(&mut &data[..])?;
+    Ok(Some(account))
+}
+
+/// Finds the permission account of `owner` among the provided accounts
+fn find_permission_info<'a, 'info>(
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    account_infos: &'a [AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    let permission_address = get_permission_address(permission_registry, owner);
+    account_infos
+        .iter()
+        .find(|account_info| *account_info.key == permission_address)
+}
+
+/// Extra accounts of the transfer hook: `extra_account_metas`, which have to start with the
+/// permission registry of `mint`, followed by the permission PDAs of the sender, receiver and
+/// authority. These are derived from the registry and the owners of the token accounts, so every
+/// holder is covered without being listed
+pub fn with_permission_metas(
+    mint: &Pubkey,
+    extra_account_metas: &[ExtraAccountMeta],
+) -> Result<Vec<ExtraAccountMeta>, ProgramError> {
+    let permission_registry = get_permission_registry_address(mint);
+    match extra_account_metas.first() {
+        Some(extra_account_meta)
+            if extra_account_meta.discriminator == 0
+                && extra_account_meta.address_config == permission_registry.to_bytes() => {}
+        _ => return Err(TransferHookError::IncorrectAccount.into()),
+    }
+
+    let owner_of = |account_index| Seed::AccountData {
+        account_index,
+        data_index: TOKEN_ACCOUNT_OWNER_OFFSET,
+        length: TOKEN_ACCOUNT_OWNER_LENGTH,
+    };
+    let registry = Seed::AccountKey {
+        index: PERMISSION_REGISTRY_INDEX,
+    };
+    // The authority permission is the sender one for a transfer signed by the owner, it is
+    // writable as well so that both resolve to the same account
+    let mut metas = extra_account_metas.to_vec();
+    metas.push(ExtraAccountMeta::new_with_seeds(
+        &[registry.clone(), owner_of(SOURCE_INDEX)],
+        false,
+        true,
+    )?);
+    metas.push(ExtraAccountMeta::new_with_seeds(
+        &[registry.clone(), owner_of(DESTINATION_INDEX)],
+        false,
+        true,
+    )?);
+    metas.push(ExtraAccountMeta::new_with_seeds(
+        &[
+            registry,
+            Seed::AccountKey {
+                index: AUTHORITY_INDEX,
+            },
+        ],
+        false,
+        true,
+    )?);
+    Ok(metas)
+}
+
+/// Checks that `authority_info` is the signing mint authority of `mint_info`
+fn check_mint_authority(mint_info: &AccountInfo, authority_info: &AccountInfo) -> ProgramResult {
     // check that the mint authority is valid without fully deserializing
     let mint_authority = inline_spl_token::get_mint_authority(&mint_info.try_borrow_data()?)?;
     let mint_authority = mint_authority.ok_or(TransferHookError::MintHasNoMintAuthority)?;
@@ -102,6 +511,23 @@ pub fn process_initialize_extra_account_metas(
     if *authority_info.key != mint_authority {
         return Err(TransferHookError::IncorrectMintAuthority.into());
     }
+    Ok(())
+}
+
+/// Processes a [InitializeExtraAccountMetaList](enum.TransferHookInstruction.html) instruction.
+pub fn process_initialize_extra_account_meta_list(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    extra_account_metas: &[ExtraAccountMeta],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let extra_account_metas_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let authority_info = next_account_info(account_info_iter)?;
+    let _system_program_info = next_account_info(account_info_iter)?;
+
+    check_mint_authority(mint_info, authority_info)?;
 
     // Check validation account
     let (expected_validation_address, bump_seed) =
@@ -111,28 +537,65 @@ pub fn process_initialize_extra_account_metas(
     }
 
     // Create the account
+    let extra_account_metas = with_permission_metas(mint_info.key, extra_account_metas)?;
     let bump_seed = [bump_seed];
     let signer_seeds = collect_extra_account_metas_signer_seeds(mint_info.key, &bump_seed);
-    let extra_account_infos = account_info_iter.as_slice();
-    let length = extra_account_infos.len();
-    let account_size = ExtraAccountMetas::size_of(length)?;
+    let account_size = ExtraAccountMetaList::size_of(extra_account_metas.len())?;
     invoke_signed(
         &system_instruction::allocate(extra_account_metas_info.key, account_size as u64),
-        &[extra_account_metas_info.clone()],
+        std::slice::from_ref(extra_account_metas_info),
         &[&signer_seeds],
     )?;
     invoke_signed(
         &system_instruction::assign(extra_account_metas_info.key, program_id),
-        &[extra_account_metas_info.clone()],
+        std::slice::from_ref(extra_account_metas_info),
         &[&signer_seeds],
     )?;
 
     // Write the data
     let mut data = extra_account_metas_info.try_borrow_mut_data()?;
-    ExtraAccountMetas::init_with_account_infos::<ExecuteInstruction>(
-        &mut data,
-        extra_account_infos,
-    )?;
+    ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &extra_account_metas)?;
+
+    Ok(())
+}
+
+/// Processes a [UpdateExtraAccountMetaList](enum.TransferHookInstruction.html) instruction. The
+/// validation account has to hold the rent of the new list already, `update_extra_account_metas`
+/// settles it with a payer instead
+pub fn process_update_extra_account_meta_list(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    extra_account_metas: &[ExtraAccountMeta],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let extra_account_metas_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let authority_info = next_account_info(account_info_iter)?;
+
+    check_mint_authority(mint_info, authority_info)?;
+
+    // Check validation account
+    let expected_validation_address = get_extra_account_metas_address(mint_info.key, program_id);
+    if expected_validation_address != *extra_account_metas_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if extra_account_metas_info.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    // Resize the account and overwrite the list
+    let extra_account_metas = with_permission_metas(mint_info.key, extra_account_metas)?;
+    let account_size = ExtraAccountMetaList::size_of(extra_account_metas.len())?;
+    if !Rent::get()?.is_exempt(extra_account_metas_info.lamports(), account_size) {
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+    extra_account_metas_info.realloc(account_size, false)?;
+
+    // The previous list has to be cleared for the TLV entry to be allocated again
+    let mut data = extra_account_metas_info.try_borrow_mut_data()?;
+    data.fill(0);
+    ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &extra_account_metas)?;
 
     Ok(())
 }
@@ -146,9 +609,17 @@ pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> P
             msg!("Instruction: Execute");
             process_execute(program_id, accounts, amount)
         }
-        TransferHookInstruction::InitializeExtraAccountMetas => {
-            msg!("Instruction: InitializeExtraAccountMetas");
-            process_initialize_extra_account_metas(program_id, accounts)
+        TransferHookInstruction::InitializeExtraAccountMetaList {
+            extra_account_metas,
+        } => {
+            msg!("Instruction: InitializeExtraAccountMetaList");
+            process_initialize_extra_account_meta_list(program_id, accounts, &extra_account_metas)
+        }
+        TransferHookInstruction::UpdateExtraAccountMetaList {
+            extra_account_metas,
+        } => {
+            msg!("Instruction: UpdateExtraAccountMetaList");
+            process_update_extra_account_meta_list(program_id, accounts, &extra_account_metas)
         }
     }
 }
@@ -8,6 +8,7 @@ use solana_program::{
 };
 use solana_program_test::{tokio::sync::Mutex, *};
 use solana_sdk::{
+    clock::Clock,
     instruction::Instruction,
     signature::Keypair,
     signer::Signer,
@@ -15,7 +16,7 @@ use solana_sdk::{
     transaction::{Transaction, TransactionError},
     transport::TransportError,
 };
-use spl_tlv_account_resolution::state::ExtraAccountMetas;
+use spl_tlv_account_resolution::{account::ExtraAccountMeta, state::ExtraAccountMetaList};
 use spl_token_client::{
     client::{
         ProgramBanksClient, ProgramBanksClientProcessTransaction, ProgramClient, SendTransaction,
@@ -23,7 +24,9 @@ use spl_token_client::{
     token::{ExtensionInitializationParams, Token, TokenError},
 };
 use spl_transfer_hook_interface::{
-    get_extra_account_metas_address, instruction::initialize_extra_account_metas,
+    error::TransferHookError,
+    get_extra_account_metas_address,
+    instruction::{execute, initialize_extra_account_meta_list},
 };
 
 async fn setup(
@@ -82,10 +85,343 @@ async fn setup_mint<T: SendTransaction>(
     token
 }
 
+/// Number of permission PDAs the program adds to the extra accounts, for the sender, receiver and
+/// authority
+const PERMISSION_METAS: usize = 3;
+
+fn to_extra_account_metas(extra_account_pubkeys: &[AccountMeta]) -> Vec<ExtraAccountMeta> {
+    extra_account_pubkeys
+        .iter()
+        .map(ExtraAccountMeta::from)
+        .collect()
+}
+
 fn keypair_clone(kp: &Keypair) -> Keypair {
     Keypair::from_bytes(&kp.to_bytes()).expect("failed to copy keypair")
 }
 
+async fn process_instructions(
+    context: &Mutex<ProgramTestContext>,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransactionError> {
+    let mut context = context.lock().await;
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        context.last_blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|error| error.unwrap())
+}
+
+/// Processes the instructions and decodes the events of type `T` from the logs
+async fn process_instructions_with_events<T: anchor_lang::Event>(
+    context: &Mutex<ProgramTestContext>,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Vec<T> {
+    let mut context = context.lock().await;
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        context.last_blockhash,
+    );
+    let result = context
+        .banks_client
+        .process_transaction_with_metadata(transaction)
+        .await
+        .unwrap();
+    result.result.unwrap();
+    result
+        .metadata
+        .unwrap()
+        .log_messages
+        .iter()
+        .filter_map(|log| log.strip_prefix("Program data: "))
+        .map(|data| base64::decode(data).unwrap())
+        .filter(|data| data[..8] == T::discriminator())
+        .map(|data| T::try_from_slice(&data[8..]).unwrap())
+        .collect()
+}
+
+/// Fetches and deserializes an account of the program
+async fn get_program_account<T: anchor_lang::AccountDeserialize>(
+    context: &Mutex<ProgramTestContext>,
+    address: Pubkey,
+) -> T {
+    let account = context
+        .lock()
+        .await
+        .banks_client
+        .get_account(address)
+        .await
+        .unwrap()
+        .unwrap();
+    T::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+/// Simulates the instruction and decodes its return data
+async fn simulate_return_data<T: anchor_lang::AnchorDeserialize>(
+    context: &Mutex<ProgramTestContext>,
+    instruction: Instruction,
+) -> T {
+    let mut context = context.lock().await;
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &[&context.payer],
+        context.last_blockhash,
+    );
+    let result = context
+        .banks_client
+        .simulate_transaction(transaction)
+        .await
+        .unwrap();
+    result.result.unwrap().unwrap();
+    let return_data = result.simulation_details.unwrap().return_data.unwrap();
+    T::try_from_slice(&return_data.data).unwrap()
+}
+
+fn add_permission_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    allowed_send: bool,
+    allowed_receive: bool,
+    expire_at: i64,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::AddPermission {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::AddPermission {
+            owner: *owner,
+            allowed_send,
+            allowed_receive,
+            expire_at,
+        }
+        .data(),
+    }
+}
+
+fn update_permission_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    allowed_send: bool,
+    allowed_receive: bool,
+    expire_at: i64,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::UpdatePermission {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::UpdatePermission {
+            owner: *owner,
+            allowed_send,
+            allowed_receive,
+            expire_at,
+        }
+        .data(),
+    }
+}
+
+fn set_transfer_limits_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    send_limits: permissioned_token::TransferLimits,
+    receive_limits: permissioned_token::TransferLimits,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetTransferLimits {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetTransferLimits {
+            owner: *owner,
+            send_limits,
+            receive_limits,
+        }
+        .data(),
+    }
+}
+
+fn assert_transfer_error(error: TokenError, error_code: permissioned_token::ErrorCode) {
+    assert_eq!(
+        error,
+        TokenError::Client(Box::new(TransportError::TransactionError(
+            TransactionError::InstructionError(0, InstructionError::Custom(error_code.into()))
+        )))
+    );
+}
+
+/// A permissioned mint with a funded sender and an empty receiver token account
+struct TransferTest {
+    context: Arc<Mutex<ProgramTestContext>>,
+    client: Arc<dyn ProgramClient<ProgramBanksClientProcessTransaction>>,
+    token: Token<ProgramBanksClientProcessTransaction>,
+    permission_registry: Pubkey,
+    mint_authority: Keypair,
+    sender: Keypair,
+    receiver: Keypair,
+    source: Pubkey,
+    destination: Pubkey,
+}
+
+impl TransferTest {
+    async fn new() -> Self {
+        let program_id = permissioned_token::ID;
+        let (context, client, payer) = setup(&program_id).await;
+
+        let sender = Keypair::new();
+        let receiver = Keypair::new();
+        let mint_authority = Keypair::new();
+
+        let token = setup_mint(
+            &spl_token_2022::id(),
+            &mint_authority.pubkey(),
+            9,
+            payer.clone(),
+            client.clone(),
+        )
+        .await;
+        let permission_registry =
+            permissioned_token::get_permission_registry_address(token.get_address());
+
+        token
+            .create_associated_token_account(&sender.pubkey())
+            .await
+            .unwrap();
+        token
+            .create_associated_token_account(&receiver.pubkey())
+            .await
+            .unwrap();
+        let source = token.get_associated_token_address(&sender.pubkey());
+        let destination = token.get_associated_token_address(&receiver.pubkey());
+        token
+            .mint_to(
+                &source,
+                &mint_authority.pubkey(),
+                1_000_000_000_000,
+                &[&mint_authority],
+            )
+            .await
+            .unwrap();
+
+        let extra_account_metas = get_extra_account_metas_address(token.get_address(), &program_id);
+        let extra_account_pubkeys = [AccountMeta::new_readonly(permission_registry, false)];
+        let rent = context.lock().await.banks_client.get_rent().await.unwrap();
+        let rent_lamports = rent.minimum_balance(
+            ExtraAccountMetaList::size_of(extra_account_pubkeys.len() + PERMISSION_METAS).unwrap(),
+        );
+        process_instructions(
+            &context,
+            &[
+                system_instruction::transfer(&payer.pubkey(), &extra_account_metas, rent_lamports),
+                initialize_extra_account_meta_list(
+                    &program_id,
+                    &extra_account_metas,
+                    token.get_address(),
+                    &mint_authority.pubkey(),
+                    &to_extra_account_metas(&extra_account_pubkeys),
+                ),
+                Instruction {
+                    program_id,
+                    accounts: permissioned_token::accounts::Initialize {
+                        authority: payer.pubkey(),
+                        mint_authority: mint_authority.pubkey(),
+                        mint: *token.get_address(),
+                        permission_registry,
+                        role_registry: permissioned_token::get_role_registry_address(
+                            &permission_registry,
+                        ),
+                        transfer_policy: permissioned_token::get_transfer_policy_address(
+                            &permission_registry,
+                        ),
+                        approved_programs: permissioned_token::get_approved_programs_address(
+                            &permission_registry,
+                        ),
+                        system_program: system_program::ID,
+                    }
+                    .to_account_metas(None),
+                    data: permissioned_token::instruction::Initialize.data(),
+                },
+            ],
+            &[&mint_authority],
+        )
+        .await
+        .unwrap();
+
+        Self {
+            context,
+            client,
+            token,
+            permission_registry,
+            mint_authority,
+            sender,
+            receiver,
+            source,
+            destination,
+        }
+    }
+
+    async fn authority(&self) -> Pubkey {
+        self.context.lock().await.payer.pubkey()
+    }
+
+    async fn now(&self) -> i64 {
+        let mut context = self.context.lock().await;
+        let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp
+    }
+
+    async fn set_now(&self, unix_timestamp: i64) {
+        let mut context = self.context.lock().await;
+        let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp = unix_timestamp;
+        context.set_sysvar(&clock);
+    }
+
+    async fn transfer(&self, amount: u64) -> Result<(), TokenError> {
+        self.token
+            .transfer(
+                &self.source,
+                &self.destination,
+                &self.sender.pubkey(),
+                amount,
+                &[&self.sender],
+            )
+            .await
+            .map(|_| ())
+    }
+}
+
 #[tokio::test]
 async fn test_permissioned_token() {
     let program_id = permissioned_token::ID;
@@ -98,12 +434,6 @@ async fn test_permissioned_token() {
     let mint_authority_pubkey = mint_authority.pubkey();
     let decimals = 9;
 
-    let permission_registry = Pubkey::find_program_address(
-        &[permissioned_token::PERMISSION_REGISTRY_SEED],
-        &permissioned_token::ID,
-    )
-    .0;
-
     let token = setup_mint(
         &token_program_id,
         &mint_authority_pubkey,
@@ -113,6 +443,13 @@ async fn test_permissioned_token() {
     )
     .await;
 
+    let permission_registry =
+        permissioned_token::get_permission_registry_address(token.get_address());
+    let sender_permission =
+        permissioned_token::get_permission_address(&permission_registry, &wallet.pubkey());
+    let receiver_permission =
+        permissioned_token::get_permission_address(&permission_registry, &second_wallet.pubkey());
+
     let extra_account_metas = get_extra_account_metas_address(token.get_address(), &program_id);
 
     token
@@ -141,8 +478,9 @@ async fn test_permissioned_token() {
     {
         let mut context: tokio::sync::MutexGuard<ProgramTestContext> = context.lock().await;
         let rent = context.banks_client.get_rent().await.unwrap();
-        let rent_lamports =
-            rent.minimum_balance(ExtraAccountMetas::size_of(extra_account_pubkeys.len()).unwrap());
+        let rent_lamports = rent.minimum_balance(
+            ExtraAccountMetaList::size_of(extra_account_pubkeys.len() + PERMISSION_METAS).unwrap(),
+        );
         let transaction = Transaction::new_signed_with_payer(
             &[
                 system_instruction::transfer(
@@ -150,12 +488,12 @@ async fn test_permissioned_token() {
                     &extra_account_metas,
                     rent_lamports,
                 ),
-                initialize_extra_account_metas(
+                initialize_extra_account_meta_list(
                     &program_id,
                     &extra_account_metas,
                     token.get_address(),
                     &mint_authority_pubkey,
-                    &extra_account_pubkeys,
+                    &to_extra_account_metas(&extra_account_pubkeys),
                 ),
             ],
             Some(&context.payer.pubkey()),
@@ -174,14 +512,25 @@ async fn test_permissioned_token() {
                 program_id: permissioned_token::ID,
                 accounts: permissioned_token::accounts::Initialize {
                     authority: context.payer.pubkey(),
+                    mint_authority: mint_authority_pubkey,
+                    mint: *token.get_address(),
                     permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permission_registry,
+                    ),
+                    transfer_policy: permissioned_token::get_transfer_policy_address(
+                        &permission_registry,
+                    ),
+                    approved_programs: permissioned_token::get_approved_programs_address(
+                        &permission_registry,
+                    ),
                     system_program: system_program::ID,
                 }
                 .to_account_metas(None),
                 data: permissioned_token::instruction::Initialize.data(),
             }],
             Some(&context.payer.pubkey()),
-            &[&context.payer],
+            &[&context.payer, &mint_authority],
             context.last_blockhash,
         );
 
@@ -220,6 +569,11 @@ async fn test_permissioned_token() {
                 accounts: permissioned_token::accounts::AddPermission {
                     authority: context.payer.pubkey(),
                     permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permission_registry,
+                    ),
+                    permission: sender_permission,
+                    system_program: system_program::ID,
                 }
                 .to_account_metas(None),
                 data: permissioned_token::instruction::AddPermission {
@@ -270,6 +624,11 @@ async fn test_permissioned_token() {
                 accounts: permissioned_token::accounts::AddPermission {
                     authority: context.payer.pubkey(),
                     permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permission_registry,
+                    ),
+                    permission: receiver_permission,
+                    system_program: system_program::ID,
                 }
                 .to_account_metas(None),
                 data: permissioned_token::instruction::AddPermission {
@@ -299,4 +658,3570 @@ async fn test_permissioned_token() {
             .await
             .unwrap();
     }
+
+    // Sender is no longer allowed to send
+    {
+        let mut context: tokio::sync::MutexGuard<ProgramTestContext> = context.lock().await;
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::UpdatePermission {
+                    authority: context.payer.pubkey(),
+                    permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permission_registry,
+                    ),
+                    permission: sender_permission,
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::UpdatePermission {
+                    owner: wallet.pubkey(),
+                    allowed_send: false,
+                    allowed_receive: true,
+                    expire_at: i64::MAX,
+                }
+                .data(),
+            }],
+            Some(&context.payer.pubkey()),
+            &[&context.payer],
+            context.last_blockhash,
+        );
+
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+    }
+    {
+        assert_eq!(
+            token
+                .transfer(&source, &destination, &wallet.pubkey(), 1, &[&wallet])
+                .await
+                .unwrap_err(),
+            TokenError::Client(Box::new(TransportError::TransactionError(
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(
+                        permissioned_token::ErrorCode::MissingPermissionForSender.into()
+                    )
+                )
+            )))
+        );
+    }
+
+    // Receiver can send back until its permission is removed
+    {
+        token
+            .transfer(
+                &destination,
+                &source,
+                &second_wallet.pubkey(),
+                1,
+                &[&second_wallet],
+            )
+            .await
+            .unwrap();
+
+        let mut context: tokio::sync::MutexGuard<ProgramTestContext> = context.lock().await;
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::RemovePermission {
+                    authority: context.payer.pubkey(),
+                    permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permission_registry,
+                    ),
+                    permission: receiver_permission,
+                    rent_recipient: context.payer.pubkey(),
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::RemovePermission {
+                    owner: second_wallet.pubkey(),
+                }
+                .data(),
+            }],
+            Some(&context.payer.pubkey()),
+            &[&context.payer],
+            context.last_blockhash,
+        );
+
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+    }
+    {
+        assert_eq!(
+            token
+                .transfer(
+                    &destination,
+                    &source,
+                    &second_wallet.pubkey(),
+                    1,
+                    &[&second_wallet]
+                )
+                .await
+                .unwrap_err(),
+            TokenError::Client(Box::new(TransportError::TransactionError(
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(
+                        permissioned_token::ErrorCode::MissingPermissionForSender.into()
+                    )
+                )
+            )))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_permission_expiry() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let now = test.now().await;
+
+    // Expired sender
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                now - 100,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::PermissionExpiredForSender,
+    );
+
+    // Expired receiver
+    process_instructions(
+        &test.context,
+        &[
+            update_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            update_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                now - 100,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::PermissionExpiredForReceiver,
+    );
+
+    // Still accepted during the grace period
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::SetGracePeriod {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::SetGracePeriod { grace_period: 3600 }.data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+}
+
+fn prune_expired_ix(
+    cranker: &Pubkey,
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::PruneExpired {
+            cranker: *cranker,
+            permission_registry: *permission_registry,
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            authority: *authority,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::PruneExpired { owner: *owner }.data(),
+    }
+}
+
+#[tokio::test]
+async fn test_prune_expired() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let now = test.now().await;
+    let cranker = Keypair::new();
+
+    process_instructions(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &cranker.pubkey(), 1_000_000_000),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                now - 100,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // A valid permission cannot be pruned
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[prune_expired_ix(
+                &cranker.pubkey(),
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+            )],
+            &[&cranker],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionNotExpired.into())
+        )
+    );
+
+    // The cranker gets its share of the rent of the expired permission
+    let sender_permission = permissioned_token::get_permission_address(
+        &test.permission_registry,
+        &test.sender.pubkey(),
+    );
+    let permission_lamports = {
+        let mut context = test.context.lock().await;
+        context
+            .banks_client
+            .get_balance(sender_permission)
+            .await
+            .unwrap()
+    };
+    process_instructions(
+        &test.context,
+        &[prune_expired_ix(
+            &cranker.pubkey(),
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+        )],
+        &[&cranker],
+    )
+    .await
+    .unwrap();
+    let mut context = test.context.lock().await;
+    assert!(context
+        .banks_client
+        .get_account(sender_permission)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(cranker.pubkey())
+            .await
+            .unwrap(),
+        1_000_000_000 + permission_lamports * permissioned_token::PRUNE_BOUNTY_PERCENT / 100
+    );
+}
+
+#[tokio::test]
+async fn test_initialize_requires_mint_authority() {
+    let program_id = permissioned_token::ID;
+    let (context, client, payer) = setup(&program_id).await;
+    let mint_authority = Keypair::new();
+    let impostor = Keypair::new();
+
+    let token = setup_mint(
+        &spl_token_2022::id(),
+        &mint_authority.pubkey(),
+        9,
+        payer.clone(),
+        client,
+    )
+    .await;
+
+    assert_eq!(
+        process_instructions(
+            &context,
+            &[Instruction {
+                program_id,
+                accounts: permissioned_token::accounts::Initialize {
+                    authority: payer.pubkey(),
+                    mint_authority: impostor.pubkey(),
+                    mint: *token.get_address(),
+                    permission_registry: permissioned_token::get_permission_registry_address(
+                        token.get_address()
+                    ),
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &permissioned_token::get_permission_registry_address(token.get_address())
+                    ),
+                    transfer_policy: permissioned_token::get_transfer_policy_address(
+                        &permissioned_token::get_permission_registry_address(token.get_address())
+                    ),
+                    approved_programs: permissioned_token::get_approved_programs_address(
+                        &permissioned_token::get_permission_registry_address(token.get_address())
+                    ),
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::Initialize.data(),
+            }],
+            &[&impostor],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::IncorrectMintAuthority.into())
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_limits() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            set_transfer_limits_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                permissioned_token::TransferLimits {
+                    max_amount: Some(100),
+                    daily_volume: Some(150),
+                    monthly_volume: None,
+                },
+                permissioned_token::TransferLimits::default(),
+            ),
+            set_transfer_limits_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                permissioned_token::TransferLimits::default(),
+                permissioned_token::TransferLimits {
+                    max_amount: None,
+                    daily_volume: None,
+                    monthly_volume: Some(160),
+                },
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Above the maximum of a single transfer
+    assert_transfer_error(
+        test.transfer(101).await.unwrap_err(),
+        permissioned_token::ErrorCode::SenderTransferLimitExceeded,
+    );
+
+    // The daily send volume is reached
+    test.transfer(100).await.unwrap();
+    test.transfer(50).await.unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::SenderTransferLimitExceeded,
+    );
+
+    // The monthly receive volume is reached once the sender has no limit
+    process_instructions(
+        &test.context,
+        &[set_transfer_limits_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            permissioned_token::TransferLimits::default(),
+            permissioned_token::TransferLimits::default(),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(10).await.unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::ReceiverTransferLimitExceeded,
+    );
+
+    // The daily volume is over the last 24 hours, not since the first transfer of the day
+    let now = test.now().await;
+    process_instructions(
+        &test.context,
+        &[
+            set_transfer_limits_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                permissioned_token::TransferLimits {
+                    max_amount: None,
+                    daily_volume: Some(150),
+                    monthly_volume: None,
+                },
+                permissioned_token::TransferLimits::default(),
+            ),
+            set_transfer_limits_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                permissioned_token::TransferLimits::default(),
+                permissioned_token::TransferLimits::default(),
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.set_now(now + 25 * 3600).await;
+    test.transfer(90).await.unwrap();
+    test.set_now(now + 37 * 3600).await;
+    test.transfer(60).await.unwrap();
+    test.set_now(now + 50 * 3600).await;
+    test.transfer(89).await.unwrap();
+    assert_transfer_error(
+        test.transfer(3).await.unwrap_err(),
+        permissioned_token::ErrorCode::SenderTransferLimitExceeded,
+    );
+}
+
+fn grant_role_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    member: &Pubkey,
+    role: permissioned_token::Role,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::GrantRole {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::GrantRole {
+            member: *member,
+            role,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_roles() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let manager = Keypair::new();
+    let pauser = Keypair::new();
+
+    process_instructions(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &manager.pubkey(), 1_000_000_000),
+            system_instruction::transfer(&authority, &pauser.pubkey(), 1_000_000_000),
+            grant_role_ix(
+                &authority,
+                &test.permission_registry,
+                &manager.pubkey(),
+                permissioned_token::Role::PermissionManager,
+            ),
+            grant_role_ix(
+                &authority,
+                &test.permission_registry,
+                &pauser.pubkey(),
+                permissioned_token::Role::Pauser,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // A pauser cannot manage permissions
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[add_permission_ix(
+                &pauser.pubkey(),
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            )],
+            &[&pauser],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::MissingRole.into())
+        )
+    );
+
+    // Nor grant roles
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[grant_role_ix(
+                &pauser.pubkey(),
+                &test.permission_registry,
+                &pauser.pubkey(),
+                permissioned_token::Role::PermissionManager,
+            )],
+            &[&pauser],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::MissingRole.into())
+        )
+    );
+
+    // A permission manager can
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &manager.pubkey(),
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &manager.pubkey(),
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[&manager],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+
+    // Until its role is revoked
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::RevokeRole {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::RevokeRole {
+                member: manager.pubkey(),
+                role: permissioned_token::Role::PermissionManager,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[update_permission_ix(
+                &manager.pubkey(),
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                false,
+                false,
+                i64::MAX,
+            )],
+            &[&manager],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::MissingRole.into())
+        )
+    );
+}
+
+fn accept_authority_ix(new_authority: &Pubkey, permission_registry: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::AcceptAuthority {
+            new_authority: *new_authority,
+            permission_registry: *permission_registry,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::AcceptAuthority.data(),
+    }
+}
+
+#[tokio::test]
+async fn test_authority_transfer() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let new_authority = Keypair::new();
+    let impostor = Keypair::new();
+
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::ProposeAuthority {
+                authority,
+                permission_registry: test.permission_registry,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::ProposeAuthority {
+                new_authority: new_authority.pubkey(),
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Only the proposed authority can accept
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[accept_authority_ix(
+                &impostor.pubkey(),
+                &test.permission_registry
+            )],
+            &[&impostor],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(
+                permissioned_token::ErrorCode::IncorrectPendingAuthority.into()
+            )
+        )
+    );
+    process_instructions(
+        &test.context,
+        &[accept_authority_ix(
+            &new_authority.pubkey(),
+            &test.permission_registry,
+        )],
+        &[&new_authority],
+    )
+    .await
+    .unwrap();
+
+    // The previous authority lost its rights
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::SetGracePeriod {
+                    authority,
+                    permission_registry: test.permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &test.permission_registry
+                    ),
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::SetGracePeriod { grace_period: 3600 }.data(),
+            }],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::MissingRole.into())
+        )
+    );
+}
+
+fn set_merkle_root_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    merkle_root: Option<[u8; 32]>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetMerkleRoot {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetMerkleRoot { merkle_root }.data(),
+    }
+}
+
+fn prove_permission_ix(
+    payer: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    proof: Vec<[u8; 32]>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::ProvePermission {
+            payer: *payer,
+            permission_registry: *permission_registry,
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::ProvePermission {
+            allowed_send: true,
+            allowed_receive: true,
+            expire_at: i64::MAX,
+            owner: *owner,
+            proof,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_merkle_permissions() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+
+    let sender_leaf =
+        permissioned_token::merkle::compute_leaf(&test.sender.pubkey(), true, true, i64::MAX);
+    let receiver_leaf =
+        permissioned_token::merkle::compute_leaf(&test.receiver.pubkey(), true, true, i64::MAX);
+    let merkle_root = permissioned_token::merkle::compute_parent(&sender_leaf, &receiver_leaf);
+
+    // A proof against another tree is rejected
+    process_instructions(
+        &test.context,
+        &[set_merkle_root_ix(
+            &authority,
+            &test.permission_registry,
+            Some(merkle_root),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[prove_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                vec![[7; 32]],
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::InvalidMerkleProof.into())
+        )
+    );
+
+    // Holders that were never added can transfer once proven
+    process_instructions(
+        &test.context,
+        &[
+            prove_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                vec![receiver_leaf],
+            ),
+            prove_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                vec![sender_leaf],
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+
+    // The proven permissions are dropped along with their root
+    let other_leaf =
+        permissioned_token::merkle::compute_leaf(&Pubkey::new_unique(), true, true, i64::MAX);
+    process_instructions(
+        &test.context,
+        &[set_merkle_root_ix(
+            &authority,
+            &test.permission_registry,
+            Some(permissioned_token::merkle::compute_parent(
+                &receiver_leaf,
+                &other_leaf,
+            )),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForSender,
+    );
+
+    // A permission updated by a permission manager cannot be proven over again
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[
+                update_permission_ix(
+                    &authority,
+                    &test.permission_registry,
+                    &test.receiver.pubkey(),
+                    true,
+                    false,
+                    i64::MAX,
+                ),
+                prove_permission_ix(
+                    &authority,
+                    &test.permission_registry,
+                    &test.receiver.pubkey(),
+                    vec![other_leaf],
+                ),
+            ],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionAlreadyExists.into())
+        )
+    );
+}
+
+fn set_holder_attributes_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    jurisdiction: [u8; 2],
+    accreditation_level: u8,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetHolderAttributes {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetHolderAttributes {
+            attributes: permissioned_token::HolderAttributes {
+                jurisdiction,
+                investor_class: 0,
+                accreditation_level,
+            },
+            owner: *owner,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_transfer_policy() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+
+    // US accredited holders may only send to US accredited holders or outside of the US
+    let us_accredited = permissioned_token::AttributeFilter {
+        jurisdiction: permissioned_token::JurisdictionFilter::Is(*b"US"),
+        investor_class: None,
+        min_accreditation_level: 1,
+    };
+    let non_us = permissioned_token::AttributeFilter {
+        jurisdiction: permissioned_token::JurisdictionFilter::IsNot(*b"US"),
+        investor_class: None,
+        min_accreditation_level: 0,
+    };
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            set_holder_attributes_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                *b"US",
+                1,
+            ),
+            set_holder_attributes_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                *b"FR",
+                0,
+            ),
+            Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::SetTransferPolicy {
+                    authority,
+                    permission_registry: test.permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &test.permission_registry,
+                    ),
+                    transfer_policy: permissioned_token::get_transfer_policy_address(
+                        &test.permission_registry,
+                    ),
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::SetTransferPolicy {
+                    transfer_rules: vec![
+                        permissioned_token::TransferRule {
+                            sender: us_accredited,
+                            receiver: us_accredited,
+                        },
+                        permissioned_token::TransferRule {
+                            sender: us_accredited,
+                            receiver: non_us,
+                        },
+                    ],
+                }
+                .data(),
+            },
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // The hook needs the transfer policy among the extra accounts once there are rules
+    assert_transfer_error(
+        test.transfer(3).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingTransferPolicy,
+    );
+    process_instructions(
+        &test.context,
+        &[update_extra_account_metas_ix(
+            &authority,
+            &test.mint_authority.pubkey(),
+            test.token.get_address(),
+            &[
+                AccountMeta::new_readonly(test.permission_registry, false),
+                AccountMeta::new_readonly(
+                    permissioned_token::get_transfer_policy_address(&test.permission_registry),
+                    false,
+                ),
+            ],
+        )],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+
+    // A US holder that is not accredited matches no rule
+    process_instructions(
+        &test.context,
+        &[set_holder_attributes_ix(
+            &authority,
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            *b"US",
+            0,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::TransferNotAllowedByPolicy,
+    );
+}
+
+fn block_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    blocked: bool,
+) -> Instruction {
+    let permission = permissioned_token::get_permission_address(permission_registry, owner);
+    if blocked {
+        Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::AddBlock {
+                authority: *authority,
+                permission_registry: *permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(permission_registry),
+                permission,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::AddBlock { owner: *owner }.data(),
+        }
+    } else {
+        Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::RemoveBlock {
+                authority: *authority,
+                permission_registry: *permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(permission_registry),
+                permission,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::RemoveBlock { owner: *owner }.data(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_denylist() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+
+    // Holders without a permission can transfer
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::SetRegistryMode {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::SetRegistryMode {
+                mode: permissioned_token::RegistryMode::Denylist,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+
+    // Unless they are blocked
+    process_instructions(
+        &test.context,
+        &[block_ix(
+            &authority,
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            true,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::ReceiverBlocked,
+    );
+
+    // The permission PDAs are added by the program whatever the extra accounts listed, so the
+    // block cannot be lifted by leaving the receiver out
+    process_instructions(
+        &test.context,
+        &[update_extra_account_metas_ix(
+            &authority,
+            &test.mint_authority.pubkey(),
+            test.token.get_address(),
+            &[AccountMeta::new_readonly(test.permission_registry, false)],
+        )],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(5).await.unwrap_err(),
+        permissioned_token::ErrorCode::ReceiverBlocked,
+    );
+
+    process_instructions(
+        &test.context,
+        &[
+            block_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                false,
+            ),
+            block_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(3).await.unwrap_err(),
+        permissioned_token::ErrorCode::SenderBlocked,
+    );
+
+    // A block cannot be pruned to lift it
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[prune_expired_ix(
+                &authority,
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionNotPrunable.into())
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_pause_and_freeze() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let pauser = Keypair::new();
+    let set_paused_accounts = permissioned_token::accounts::SetPaused {
+        authority: pauser.pubkey(),
+        permission_registry: test.permission_registry,
+        role_registry: permissioned_token::get_role_registry_address(&test.permission_registry),
+    };
+
+    process_instructions(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &pauser.pubkey(), 1_000_000_000),
+            grant_role_ix(
+                &authority,
+                &test.permission_registry,
+                &pauser.pubkey(),
+                permissioned_token::Role::Pauser,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            Instruction {
+                program_id: permissioned_token::ID,
+                accounts: set_paused_accounts.to_account_metas(None),
+                data: permissioned_token::instruction::Pause.data(),
+            },
+        ],
+        &[&pauser],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::TransfersPaused,
+    );
+
+    // Unpaused but the receiver is frozen
+    process_instructions(
+        &test.context,
+        &[
+            Instruction {
+                program_id: permissioned_token::ID,
+                accounts: set_paused_accounts.to_account_metas(None),
+                data: permissioned_token::instruction::Unpause.data(),
+            },
+            Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::FreezeHolder {
+                    authority: pauser.pubkey(),
+                    permission_registry: test.permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &test.permission_registry,
+                    ),
+                    permission: permissioned_token::get_permission_address(
+                        &test.permission_registry,
+                        &test.receiver.pubkey(),
+                    ),
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::FreezeHolder {
+                    owner: test.receiver.pubkey(),
+                }
+                .data(),
+            },
+        ],
+        &[&pauser],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::HolderFrozen,
+    );
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[prune_expired_ix(
+                &authority,
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionNotPrunable.into())
+        )
+    );
+
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::ThawHolder {
+                authority: pauser.pubkey(),
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+                permission: permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.receiver.pubkey(),
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::ThawHolder {
+                owner: test.receiver.pubkey(),
+            }
+            .data(),
+        }],
+        &[&pauser],
+    )
+    .await
+    .unwrap();
+    test.transfer(3).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_permission_events() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+
+    let events: Vec<permissioned_token::PermissionAdded> = process_instructions_with_events(
+        &test.context,
+        &[add_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            true,
+            false,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].permission_registry, test.permission_registry);
+    assert_eq!(events[0].owner, test.sender.pubkey());
+    assert!(events[0].allowed_send);
+    assert!(!events[0].allowed_receive);
+    assert_eq!(events[0].expire_at, i64::MAX);
+
+    let events: Vec<permissioned_token::PermissionRemoved> = process_instructions_with_events(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::RemovePermission {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+                permission: permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.sender.pubkey(),
+                ),
+                rent_recipient: authority,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::RemovePermission {
+                owner: test.sender.pubkey(),
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].owner, test.sender.pubkey());
+
+    // Blocking a holder without a permission creates it
+    let events: Vec<permissioned_token::PermissionAdded> = process_instructions_with_events(
+        &test.context,
+        &[block_ix(
+            &authority,
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            true,
+        )],
+        &[],
+    )
+    .await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].owner, test.receiver.pubkey());
+    assert!(!events[0].allowed_receive);
+
+    let events: Vec<permissioned_token::PermissionUpdated> = process_instructions_with_events(
+        &test.context,
+        &[
+            set_holder_attributes_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                *b"US",
+                1,
+            ),
+            block_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                false,
+            ),
+        ],
+        &[],
+    )
+    .await;
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|event| event.owner == test.receiver.pubkey()));
+
+    let events: Vec<permissioned_token::PermissionRequested> = process_instructions_with_events(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &test.sender.pubkey(), 1_000_000_000),
+            request_permission_ix(
+                &test.sender.pubkey(),
+                &test.permission_registry,
+                Some([1; 32]),
+            ),
+        ],
+        &[&test.sender],
+    )
+    .await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].owner, test.sender.pubkey());
+    assert_eq!(events[0].reference_hash, Some([1; 32]));
+}
+
+fn update_extra_account_metas_ix(
+    payer: &Pubkey,
+    mint_authority: &Pubkey,
+    mint: &Pubkey,
+    extra_account_pubkeys: &[AccountMeta],
+) -> Instruction {
+    let mut accounts = permissioned_token::accounts::UpdateExtraAccountMetas {
+        payer: *payer,
+        mint_authority: *mint_authority,
+        mint: *mint,
+        extra_account_metas: get_extra_account_metas_address(mint, &permissioned_token::ID),
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+    accounts.extend_from_slice(extra_account_pubkeys);
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts,
+        data: permissioned_token::instruction::UpdateExtraAccountMetas.data(),
+    }
+}
+
+#[tokio::test]
+async fn test_update_extra_account_metas() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let mint = *test.token.get_address();
+    let transfer_policy =
+        permissioned_token::get_transfer_policy_address(&test.permission_registry);
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(1).await.unwrap();
+
+    // Only the mint authority can update the list
+    let impostor = Keypair::new();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[update_extra_account_metas_ix(
+                &authority,
+                &impostor.pubkey(),
+                &mint,
+                &[AccountMeta::new_readonly(test.permission_registry, false)],
+            )],
+            &[&impostor],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::IncorrectMintAuthority.into())
+        )
+    );
+
+    // The list has to start with the permission registry
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[update_extra_account_metas_ix(
+                &authority,
+                &test.mint_authority.pubkey(),
+                &mint,
+                &[AccountMeta::new_readonly(transfer_policy, false)],
+            )],
+            &[&test.mint_authority],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TransferHookError::IncorrectAccount as u32)
+        )
+    );
+
+    // Holders are not listed, the hook resolves their permission PDAs from the token accounts
+    let newcomer = Keypair::new();
+    test.token
+        .create_associated_token_account(&newcomer.pubkey())
+        .await
+        .unwrap();
+    process_instructions(
+        &test.context,
+        &[add_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &newcomer.pubkey(),
+            true,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.token
+        .transfer(
+            &test.source,
+            &test.token.get_associated_token_address(&newcomer.pubkey()),
+            &test.sender.pubkey(),
+            2,
+            &[&test.sender],
+        )
+        .await
+        .unwrap();
+
+    // The list grows, the validation account is resized to hold it
+    process_instructions(
+        &test.context,
+        &[update_extra_account_metas_ix(
+            &authority,
+            &test.mint_authority.pubkey(),
+            &mint,
+            &[
+                AccountMeta::new_readonly(test.permission_registry, false),
+                AccountMeta::new_readonly(transfer_policy, false),
+            ],
+        )],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+    let extra_account_metas = test
+        .context
+        .lock()
+        .await
+        .banks_client
+        .get_account(get_extra_account_metas_address(
+            &mint,
+            &permissioned_token::ID,
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        extra_account_metas.data.len(),
+        ExtraAccountMetaList::size_of(2 + PERMISSION_METAS).unwrap()
+    );
+    test.transfer(3).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_execute_outside_of_transfer() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let mint = *test.token.get_address();
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let execute_ix = |source: &Pubkey, destination: &Pubkey| {
+        let mut instruction = execute(
+            &permissioned_token::ID,
+            source,
+            &mint,
+            destination,
+            &test.sender.pubkey(),
+            &get_extra_account_metas_address(&mint, &permissioned_token::ID),
+            1,
+        );
+        instruction.accounts.extend([
+            AccountMeta::new_readonly(test.permission_registry, false),
+            AccountMeta::new(
+                permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.sender.pubkey(),
+                ),
+                false,
+            ),
+            AccountMeta::new(
+                permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.receiver.pubkey(),
+                ),
+                false,
+            ),
+            AccountMeta::new(
+                permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.sender.pubkey(),
+                ),
+                false,
+            ),
+        ]);
+        instruction
+    };
+
+    // Calling the hook directly does not approve anything
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[execute_ix(&test.source, &test.destination)],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(
+                permissioned_token::ErrorCode::ProgramCalledOutsideOfTransfer.into()
+            )
+        )
+    );
+
+    // Nor with accounts that are not token accounts of the mint
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[execute_ix(&test.permission_registry, &test.destination)],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::IncorrectTokenAccount.into())
+        )
+    );
+
+    test.transfer(1).await.unwrap();
+}
+
+fn set_delegate_permission_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    allowed_delegate: bool,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetDelegatePermission {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetDelegatePermission {
+            allowed_delegate,
+            owner: *owner,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_delegate() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let delegate = Keypair::new();
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.token
+        .approve(
+            &test.source,
+            &delegate.pubkey(),
+            &test.sender.pubkey(),
+            1_000,
+            &[&test.sender],
+        )
+        .await
+        .unwrap();
+    let delegate_transfer = |amount| {
+        test.token.transfer(
+            &test.source,
+            &test.destination,
+            &delegate.pubkey(),
+            amount,
+            &[&delegate],
+        )
+    };
+
+    // The delegate needs a permission of its own
+    assert_transfer_error(
+        delegate_transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForDelegate,
+    );
+    process_instructions(
+        &test.context,
+        &[add_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &delegate.pubkey(),
+            true,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        delegate_transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForDelegate,
+    );
+
+    process_instructions(
+        &test.context,
+        &[set_delegate_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &delegate.pubkey(),
+            true,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    delegate_transfer(3).await.unwrap();
+
+    // Only the owner can transfer once required by the registry
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::SetOwnerSignedOnly {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::SetOwnerSignedOnly {
+                owner_signed_only: true,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        delegate_transfer(4).await.unwrap_err(),
+        permissioned_token::ErrorCode::TransferNotSignedByOwner,
+    );
+    test.transfer(5).await.unwrap();
+}
+
+fn set_approved_programs_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    programs: Vec<Pubkey>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetApprovedPrograms {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            approved_programs: permissioned_token::get_approved_programs_address(
+                permission_registry,
+            ),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetApprovedPrograms { programs }.data(),
+    }
+}
+
+fn approve_program_holder_ix(
+    payer: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    program_id: &Pubkey,
+    seeds: Vec<Vec<u8>>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::ApproveProgramHolder {
+            payer: *payer,
+            permission_registry: *permission_registry,
+            approved_programs: permissioned_token::get_approved_programs_address(
+                permission_registry,
+            ),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::ApproveProgramHolder {
+            owner: *owner,
+            program_id: *program_id,
+            seeds,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_program_holders() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let mint = *test.token.get_address();
+
+    // A pool of an on-chain venue, its token account is owned by a PDA
+    let venue = Pubkey::new_unique();
+    let (pool, bump) = Pubkey::find_program_address(&[b"pool"], &venue);
+    test.token
+        .create_associated_token_account(&pool)
+        .await
+        .unwrap();
+    let pool_account = test.token.get_associated_token_address(&pool);
+    let transfer_to_pool = |amount| {
+        test.token.transfer(
+            &test.source,
+            &pool_account,
+            &test.sender.pubkey(),
+            amount,
+            &[&test.sender],
+        )
+    };
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            update_extra_account_metas_ix(
+                &authority,
+                &test.mint_authority.pubkey(),
+                &mint,
+                &[
+                    AccountMeta::new_readonly(test.permission_registry, false),
+                    AccountMeta::new_readonly(
+                        permissioned_token::get_approved_programs_address(
+                            &test.permission_registry,
+                        ),
+                        false,
+                    ),
+                ],
+            ),
+        ],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        transfer_to_pool(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForReceiver,
+    );
+
+    // Anyone can approve the PDAs of approved programs only
+    let seeds = vec![b"pool".to_vec(), vec![bump]];
+    let other_owner = Pubkey::new_unique();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[approve_program_holder_ix(
+                &authority,
+                &test.permission_registry,
+                &pool,
+                &venue,
+                seeds.clone(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::ProgramNotApproved.into())
+        )
+    );
+    process_instructions(
+        &test.context,
+        &[set_approved_programs_ix(
+            &authority,
+            &test.permission_registry,
+            vec![venue],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[approve_program_holder_ix(
+                &authority,
+                &test.permission_registry,
+                &other_owner,
+                &venue,
+                seeds.clone(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(
+                permissioned_token::ErrorCode::InvalidProgramHolderSeeds.into()
+            )
+        )
+    );
+    process_instructions(
+        &test.context,
+        &[approve_program_holder_ix(
+            &authority,
+            &test.permission_registry,
+            &pool,
+            &venue,
+            seeds.clone(),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    transfer_to_pool(2).await.unwrap();
+
+    // Approving the PDA again does not undo what a permission manager set
+    {
+        let mut context = test.context.lock().await;
+        context.last_blockhash = context.get_new_latest_blockhash().await.unwrap();
+    }
+    process_instructions(
+        &test.context,
+        &[
+            update_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &pool,
+                true,
+                false,
+                i64::MAX,
+            ),
+            approve_program_holder_ix(&authority, &test.permission_registry, &pool, &venue, seeds),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        transfer_to_pool(3).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForReceiver,
+    );
+    process_instructions(
+        &test.context,
+        &[update_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &pool,
+            true,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Removing the program revokes its PDAs
+    process_instructions(
+        &test.context,
+        &[set_approved_programs_ix(
+            &authority,
+            &test.permission_registry,
+            vec![],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        transfer_to_pool(4).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForReceiver,
+    );
+
+    // The permission of a holder added by a permission manager cannot be taken over
+    let (vault, bump) = Pubkey::find_program_address(&[b"vault"], &venue);
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[
+                set_approved_programs_ix(&authority, &test.permission_registry, vec![venue]),
+                add_permission_ix(
+                    &authority,
+                    &test.permission_registry,
+                    &vault,
+                    true,
+                    false,
+                    i64::MAX,
+                ),
+                approve_program_holder_ix(
+                    &authority,
+                    &test.permission_registry,
+                    &vault,
+                    &venue,
+                    vec![b"vault".to_vec(), vec![bump]],
+                ),
+            ],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            2,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionAlreadyExists.into())
+        )
+    );
+}
+
+fn set_counterparties_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    counterparties: Vec<Pubkey>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetCounterparties {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetCounterparties {
+            counterparties,
+            owner: *owner,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_counterparties() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let redemption_wallet = Pubkey::new_unique();
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[set_counterparties_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                (0..=permissioned_token::MAX_COUNTERPARTIES)
+                    .map(|_| Pubkey::new_unique())
+                    .collect(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::TooManyCounterparties.into())
+        )
+    );
+
+    // The sender can only send to the redemption wallet
+    process_instructions(
+        &test.context,
+        &[set_counterparties_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            vec![redemption_wallet],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::CounterpartyNotAllowedForSender,
+    );
+
+    process_instructions(
+        &test.context,
+        &[set_counterparties_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            vec![redemption_wallet, test.receiver.pubkey()],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(2).await.unwrap();
+
+    // The receiver can only receive from the redemption wallet
+    process_instructions(
+        &test.context,
+        &[set_counterparties_ix(
+            &authority,
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            vec![redemption_wallet],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(3).await.unwrap_err(),
+        permissioned_token::ErrorCode::CounterpartyNotAllowedForReceiver,
+    );
+
+    // An empty list lifts the restriction
+    process_instructions(
+        &test.context,
+        &[set_counterparties_ix(
+            &authority,
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            vec![],
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(4).await.unwrap();
+}
+
+fn grant_lockup_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    token_account: &Pubkey,
+    amount: u64,
+    unlock_at: i64,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::GrantLockup {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            token_account: *token_account,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::GrantLockup {
+            amount,
+            unlock_at,
+            owner: *owner,
+        }
+        .data(),
+    }
+}
+
+fn get_unlocked_balance_ix(
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    token_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::GetUnlockedBalance {
+            permission_registry: *permission_registry,
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            token_account: *token_account,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::GetUnlockedBalance { owner: *owner }.data(),
+    }
+}
+
+#[tokio::test]
+async fn test_lockups() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let now = test.now().await;
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            Instruction {
+                program_id: permissioned_token::ID,
+                accounts: permissioned_token::accounts::SetLockupPeriod {
+                    authority,
+                    permission_registry: test.permission_registry,
+                    role_registry: permissioned_token::get_role_registry_address(
+                        &test.permission_registry,
+                    ),
+                }
+                .to_account_metas(None),
+                data: permissioned_token::instruction::SetLockupPeriod { lockup_period: 100 }
+                    .data(),
+            },
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // The received tokens are locked for the lockup period
+    test.transfer(1_000).await.unwrap();
+    let send_back = |amount| {
+        test.token.transfer(
+            &test.destination,
+            &test.source,
+            &test.receiver.pubkey(),
+            amount,
+            &[&test.receiver],
+        )
+    };
+    assert_transfer_error(
+        send_back(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::TokensLocked,
+    );
+    let unlocked_balance: u64 = simulate_return_data(
+        &test.context,
+        get_unlocked_balance_ix(
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            &test.destination,
+        ),
+    )
+    .await;
+    assert_eq!(unlocked_balance, 0);
+
+    let mut clock: Clock = test
+        .context
+        .lock()
+        .await
+        .banks_client
+        .get_sysvar()
+        .await
+        .unwrap();
+    clock.unix_timestamp = now + 200;
+    test.context.lock().await.set_sysvar(&clock);
+    send_back(2).await.unwrap();
+
+    // Minted tokens are locked by the authority, the sender keeps 10 unlocked tokens on top of
+    // the 2 tokens sent back which are locked as well
+    let balance = test
+        .token
+        .get_account_info(&test.source)
+        .await
+        .unwrap()
+        .base
+        .amount;
+    process_instructions(
+        &test.context,
+        &[grant_lockup_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            &test.source,
+            balance - 12,
+            now + 1_000,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    let unlocked_balance: u64 = simulate_return_data(
+        &test.context,
+        get_unlocked_balance_ix(
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            &test.source,
+        ),
+    )
+    .await;
+    assert_eq!(unlocked_balance, 10);
+    assert_transfer_error(
+        test.transfer(11).await.unwrap_err(),
+        permissioned_token::ErrorCode::TokensLocked,
+    );
+    test.transfer(10).await.unwrap();
+
+    // Tokens are locked in the token account they were received or granted in, the tokens minted
+    // to another account of the sender can all be sent
+    let second_account = Keypair::new();
+    test.token
+        .create_auxiliary_token_account(&second_account, &test.sender.pubkey())
+        .await
+        .unwrap();
+    test.token
+        .mint_to(
+            &second_account.pubkey(),
+            &test.mint_authority.pubkey(),
+            500,
+            &[&test.mint_authority],
+        )
+        .await
+        .unwrap();
+    let unlocked_balance: u64 = simulate_return_data(
+        &test.context,
+        get_unlocked_balance_ix(
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            &second_account.pubkey(),
+        ),
+    )
+    .await;
+    assert_eq!(unlocked_balance, 500);
+    test.token
+        .transfer(
+            &second_account.pubkey(),
+            &test.destination,
+            &test.sender.pubkey(),
+            500,
+            &[&test.sender],
+        )
+        .await
+        .unwrap();
+
+    // Receivers without a permission account to lock their tokens in are rejected in denylist
+    // mode too
+    test.set_now(now + 2_000).await;
+    let newcomer = Keypair::new();
+    test.token
+        .create_associated_token_account(&newcomer.pubkey())
+        .await
+        .unwrap();
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::SetRegistryMode {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::SetRegistryMode {
+                mode: permissioned_token::RegistryMode::Denylist,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.token
+            .transfer(
+                &test.source,
+                &test.token.get_associated_token_address(&newcomer.pubkey()),
+                &test.sender.pubkey(),
+                1,
+                &[&test.sender],
+            )
+            .await
+            .unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForLockup,
+    );
+}
+
+fn set_holder_tracking_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    track_holders: bool,
+    max_holders: Option<u64>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetHolderTracking {
+            authority: *authority,
+            permission_registry: *permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(permission_registry),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetHolderTracking {
+            track_holders,
+            max_holders,
+        }
+        .data(),
+    }
+}
+
+fn sync_holder_ix(
+    authority: &Pubkey,
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    token_accounts: &[Pubkey],
+) -> Instruction {
+    let mut accounts = permissioned_token::accounts::SyncHolder {
+        authority: *authority,
+        permission_registry: *permission_registry,
+        role_registry: permissioned_token::get_role_registry_address(permission_registry),
+        permission: permissioned_token::get_permission_address(permission_registry, owner),
+    }
+    .to_account_metas(None);
+    accounts.extend(
+        token_accounts
+            .iter()
+            .map(|token_account| AccountMeta::new_readonly(*token_account, false)),
+    );
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts,
+        data: permissioned_token::instruction::SyncHolder { owner: *owner }.data(),
+    }
+}
+
+#[tokio::test]
+async fn test_holder_index() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let mint = *test.token.get_address();
+    let sender_permission = permissioned_token::get_permission_address(
+        &test.permission_registry,
+        &test.sender.pubkey(),
+    );
+    let receiver_permission = permissioned_token::get_permission_address(
+        &test.permission_registry,
+        &test.receiver.pubkey(),
+    );
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            set_holder_tracking_ix(&authority, &test.permission_registry, true, None),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // The hook updates the holder count in the registry
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::PermissionRegistryNotWritable,
+    );
+    process_instructions(
+        &test.context,
+        &[update_extra_account_metas_ix(
+            &authority,
+            &test.mint_authority.pubkey(),
+            &mint,
+            &[AccountMeta::new(test.permission_registry, false)],
+        )],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+
+    // The minted tokens of the sender are synced, the receiver would be a holder too many
+    process_instructions(
+        &test.context,
+        &[
+            sync_holder_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                &[test.source],
+            ),
+            set_holder_tracking_ix(&authority, &test.permission_registry, true, Some(1)),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::MaxHoldersReached,
+    );
+
+    process_instructions(
+        &test.context,
+        &[set_holder_tracking_ix(
+            &authority,
+            &test.permission_registry,
+            true,
+            Some(2),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(3).await.unwrap();
+    let permission_registry: permissioned_token::PermissionRegistry =
+        get_program_account(&test.context, test.permission_registry).await;
+    assert_eq!(permission_registry.holder_count, 2);
+    let permission: permissioned_token::Permission =
+        get_program_account(&test.context, receiver_permission).await;
+    assert_eq!(permission.balance, 3);
+    assert!(permission.first_acquired_at > 0);
+
+    // Sending everything leaves the index
+    test.token
+        .transfer(
+            &test.destination,
+            &test.source,
+            &test.receiver.pubkey(),
+            3,
+            &[&test.receiver],
+        )
+        .await
+        .unwrap();
+    let permission_registry: permissioned_token::PermissionRegistry =
+        get_program_account(&test.context, test.permission_registry).await;
+    assert_eq!(permission_registry.holder_count, 1);
+
+    // The balance of a holder spans its token accounts, emptying one of them keeps it indexed
+    let second_account = Keypair::new();
+    test.token
+        .create_auxiliary_token_account(&second_account, &test.receiver.pubkey())
+        .await
+        .unwrap();
+    test.transfer(4).await.unwrap();
+    test.token
+        .transfer(
+            &test.source,
+            &second_account.pubkey(),
+            &test.sender.pubkey(),
+            5,
+            &[&test.sender],
+        )
+        .await
+        .unwrap();
+    test.token
+        .transfer(
+            &test.destination,
+            &test.source,
+            &test.receiver.pubkey(),
+            4,
+            &[&test.receiver],
+        )
+        .await
+        .unwrap();
+    let permission_registry: permissioned_token::PermissionRegistry =
+        get_program_account(&test.context, test.permission_registry).await;
+    assert_eq!(permission_registry.holder_count, 2);
+    let permission: permissioned_token::Permission =
+        get_program_account(&test.context, receiver_permission).await;
+    assert_eq!(permission.balance, 5);
+
+    // A holder of tokens cannot be pruned, removing its permission takes it off the index
+    process_instructions(
+        &test.context,
+        &[update_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            true,
+            true,
+            0,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[prune_expired_ix(
+                &authority,
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::PermissionNotPrunable.into())
+        )
+    );
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::RemovePermission {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+                permission: sender_permission,
+                rent_recipient: authority,
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::RemovePermission {
+                owner: test.sender.pubkey(),
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    let permission_registry: permissioned_token::PermissionRegistry =
+        get_program_account(&test.context, test.permission_registry).await;
+    assert_eq!(permission_registry.holder_count, 1);
+
+    // Whatever the mode, holders without a permission account cannot receive since they would
+    // not be counted
+    let newcomer = Keypair::new();
+    test.token
+        .create_associated_token_account(&newcomer.pubkey())
+        .await
+        .unwrap();
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::SetRegistryMode {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::SetRegistryMode {
+                mode: permissioned_token::RegistryMode::Denylist,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.token
+            .transfer(
+                &test.source,
+                &test.token.get_associated_token_address(&newcomer.pubkey()),
+                &test.sender.pubkey(),
+                6,
+                &[&test.sender],
+            )
+            .await
+            .unwrap_err(),
+        permissioned_token::ErrorCode::HolderNotIndexed,
+    );
+}
+
+fn get_snapshot_balance_ix(
+    permission_registry: &Pubkey,
+    owner: &Pubkey,
+    token_account: &Pubkey,
+    snapshot_id: u64,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::GetSnapshotBalance {
+            permission_registry: *permission_registry,
+            permission: permissioned_token::get_permission_address(permission_registry, owner),
+            token_account: *token_account,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::GetSnapshotBalance {
+            owner: *owner,
+            snapshot_id,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_snapshots() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let create_snapshot_ix = Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::CreateSnapshot {
+            authority,
+            permission_registry: test.permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(&test.permission_registry),
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::CreateSnapshot.data(),
+    };
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                i64::MAX,
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(10).await.unwrap();
+
+    let snapshots = process_instructions_with_events::<permissioned_token::SnapshotCreated>(
+        &test.context,
+        &[create_snapshot_ix.clone()],
+        &[],
+    )
+    .await;
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].snapshot_id, 1);
+
+    // The first transfer after the snapshot records the balances before it
+    test.transfer(20).await.unwrap();
+    test.transfer(30).await.unwrap();
+    let sender_balance = test
+        .token
+        .get_account_info(&test.source)
+        .await
+        .unwrap()
+        .base
+        .amount;
+    let snapshot_balance: u64 = simulate_return_data(
+        &test.context,
+        get_snapshot_balance_ix(
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            &test.source,
+            1,
+        ),
+    )
+    .await;
+    assert_eq!(snapshot_balance, sender_balance + 50);
+    let snapshot_balance: u64 = simulate_return_data(
+        &test.context,
+        get_snapshot_balance_ix(
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            &test.destination,
+            1,
+        ),
+    )
+    .await;
+    assert_eq!(snapshot_balance, 10);
+
+    // Holders which did not move since the snapshot have their current balance, the snapshot is
+    // created again with a new blockhash
+    {
+        let mut context = test.context.lock().await;
+        context.last_blockhash = context.get_new_latest_blockhash().await.unwrap();
+    }
+    process_instructions(&test.context, &[create_snapshot_ix.clone()], &[])
+        .await
+        .unwrap();
+    let snapshot_balance: u64 = simulate_return_data(
+        &test.context,
+        get_snapshot_balance_ix(
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            &test.destination,
+            2,
+        ),
+    )
+    .await;
+    assert_eq!(snapshot_balance, 60);
+
+    // Tokens minted after the snapshot are kept out by recording the balance beforehand, which
+    // only a permission manager can do
+    let outsider = Keypair::new();
+    let record_checkpoint_ix = |authority: &Pubkey| Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::RecordCheckpoint {
+            authority: *authority,
+            permission_registry: test.permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(&test.permission_registry),
+            permission: permissioned_token::get_permission_address(
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+            ),
+            token_account: test.destination,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::RecordCheckpoint {
+            owner: test.receiver.pubkey(),
+        }
+        .data(),
+    };
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[record_checkpoint_ix(&outsider.pubkey())],
+            &[&outsider],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::MissingRole.into())
+        )
+    );
+    process_instructions(&test.context, &[record_checkpoint_ix(&authority)], &[])
+        .await
+        .unwrap();
+    test.token
+        .mint_to(
+            &test.destination,
+            &test.mint_authority.pubkey(),
+            40,
+            &[&test.mint_authority],
+        )
+        .await
+        .unwrap();
+    let snapshot_balance: u64 = simulate_return_data(
+        &test.context,
+        get_snapshot_balance_ix(
+            &test.permission_registry,
+            &test.receiver.pubkey(),
+            &test.destination,
+            2,
+        ),
+    )
+    .await;
+    assert_eq!(snapshot_balance, 60);
+
+    // Checkpoints are kept per token account, tokens received in another account of the holder
+    // leave the balance of the first one at the snapshot
+    let second_account = Keypair::new();
+    test.token
+        .create_auxiliary_token_account(&second_account, &test.receiver.pubkey())
+        .await
+        .unwrap();
+    {
+        let mut context = test.context.lock().await;
+        context.last_blockhash = context.get_new_latest_blockhash().await.unwrap();
+    }
+    process_instructions(&test.context, &[create_snapshot_ix], &[])
+        .await
+        .unwrap();
+    test.token
+        .transfer(
+            &test.source,
+            &second_account.pubkey(),
+            &test.sender.pubkey(),
+            5,
+            &[&test.sender],
+        )
+        .await
+        .unwrap();
+    for (token_account, balance) in [(test.destination, 100), (second_account.pubkey(), 0)] {
+        let snapshot_balance: u64 = simulate_return_data(
+            &test.context,
+            get_snapshot_balance_ix(
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                &token_account,
+                3,
+            ),
+        )
+        .await;
+        assert_eq!(snapshot_balance, balance);
+    }
+
+    assert_eq!(
+        process_instructions(
+            &test.context,
+            &[get_snapshot_balance_ix(
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                &test.destination,
+                4,
+            )],
+            &[],
+        )
+        .await
+        .unwrap_err(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(permissioned_token::ErrorCode::InvalidSnapshotId.into())
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_check_transfer() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let now = test.now().await;
+    let check_transfer_ix = |source, amount| {
+        let mut instruction = Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::CheckTransfer {
+                permission_registry: test.permission_registry,
+                source,
+                destination: test.destination,
+                authority: test.sender.pubkey(),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::CheckTransfer { amount }.data(),
+        };
+        instruction.accounts.extend([
+            AccountMeta::new_readonly(
+                permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.sender.pubkey(),
+                ),
+                false,
+            ),
+            AccountMeta::new_readonly(
+                permissioned_token::get_permission_address(
+                    &test.permission_registry,
+                    &test.receiver.pubkey(),
+                ),
+                false,
+            ),
+        ]);
+        instruction
+    };
+
+    process_instructions(
+        &test.context,
+        &[add_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            true,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    let verdict: permissioned_token::TransferVerdict =
+        simulate_return_data(&test.context, check_transfer_ix(test.source, 1)).await;
+    assert_eq!(
+        verdict,
+        permissioned_token::TransferVerdict {
+            allowed: false,
+            error_code: Some(
+                u32::from(permissioned_token::ErrorCode::MissingPermissionForReceiver).into()
+            ),
+            expire_at: Some(i64::MAX),
+        }
+    );
+
+    // Accounts the hook would reject are part of the verdict too
+    let verdict: permissioned_token::TransferVerdict = simulate_return_data(
+        &test.context,
+        check_transfer_ix(test.permission_registry, 1),
+    )
+    .await;
+    assert_eq!(
+        verdict,
+        permissioned_token::TransferVerdict {
+            allowed: false,
+            error_code: Some(
+                u32::from(permissioned_token::ErrorCode::IncorrectTokenAccount).into()
+            ),
+            expire_at: None,
+        }
+    );
+
+    process_instructions(
+        &test.context,
+        &[
+            add_permission_ix(
+                &authority,
+                &test.permission_registry,
+                &test.receiver.pubkey(),
+                true,
+                true,
+                now + 1000,
+            ),
+            set_transfer_limits_ix(
+                &authority,
+                &test.permission_registry,
+                &test.sender.pubkey(),
+                permissioned_token::TransferLimits {
+                    max_amount: Some(100),
+                    daily_volume: None,
+                    monthly_volume: None,
+                },
+                permissioned_token::TransferLimits::default(),
+            ),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    let verdict: permissioned_token::TransferVerdict =
+        simulate_return_data(&test.context, check_transfer_ix(test.source, 1)).await;
+    assert_eq!(
+        verdict,
+        permissioned_token::TransferVerdict {
+            allowed: true,
+            error_code: None,
+            expire_at: Some(now + 1000),
+        }
+    );
+    let verdict: permissioned_token::TransferVerdict =
+        simulate_return_data(&test.context, check_transfer_ix(test.source, 101)).await;
+    assert_eq!(
+        verdict.error_code,
+        Some(u32::from(permissioned_token::ErrorCode::SenderTransferLimitExceeded).into())
+    );
+
+    // The permission PDAs have to be passed whether the permissions exist or not
+    let mut instruction = check_transfer_ix(test.source, 1);
+    instruction.accounts.pop();
+    let verdict: permissioned_token::TransferVerdict =
+        simulate_return_data(&test.context, instruction).await;
+    assert_eq!(
+        verdict.error_code,
+        Some(u32::from(permissioned_token::ErrorCode::MissingPermissionAccount).into())
+    );
+
+    // The verdict matches the transfer hook
+    test.transfer(1).await.unwrap();
+    assert_transfer_error(
+        test.transfer(101).await.unwrap_err(),
+        permissioned_token::ErrorCode::SenderTransferLimitExceeded,
+    );
+}
+
+#[tokio::test]
+async fn test_credentials() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let mint = *test.token.get_address();
+    let payer = Arc::new(keypair_clone(&test.context.lock().await.payer));
+
+    // Non-transferable credentials issued by a KYC provider
+    let kyc_provider = Keypair::new();
+    let credential_mint = Keypair::new();
+    let credential = Token::new(
+        test.client.clone(),
+        &spl_token_2022::id(),
+        &credential_mint.pubkey(),
+        Some(0),
+        payer,
+    );
+    credential
+        .create_mint(
+            &kyc_provider.pubkey(),
+            Some(&kyc_provider.pubkey()),
+            vec![ExtensionInitializationParams::NonTransferable],
+            &[&credential_mint],
+        )
+        .await
+        .unwrap();
+    for owner in [test.sender.pubkey(), test.receiver.pubkey()] {
+        credential
+            .create_associated_token_account(&owner)
+            .await
+            .unwrap();
+    }
+    let sender_credential = credential.get_associated_token_address(&test.sender.pubkey());
+    let receiver_credential = credential.get_associated_token_address(&test.receiver.pubkey());
+
+    process_instructions(
+        &test.context,
+        &[update_extra_account_metas_ix(
+            &authority,
+            &test.mint_authority.pubkey(),
+            &mint,
+            &[
+                AccountMeta::new_readonly(test.permission_registry, false),
+                AccountMeta::new_readonly(credential_mint.pubkey(), false),
+                AccountMeta::new_readonly(sender_credential, false),
+                AccountMeta::new_readonly(receiver_credential, false),
+            ],
+        )],
+        &[&test.mint_authority],
+    )
+    .await
+    .unwrap();
+    credential
+        .mint_to(
+            &sender_credential,
+            &kyc_provider.pubkey(),
+            1,
+            &[&kyc_provider],
+        )
+        .await
+        .unwrap();
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForSender,
+    );
+
+    // Only non-transferable mints of the KYC provider are accepted as credentials
+    let set_credential_mint_ix = |credential_mint, kyc_provider| Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::SetCredentialMint {
+            authority,
+            permission_registry: test.permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(&test.permission_registry),
+            credential_mint,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::SetCredentialMint { kyc_provider }.data(),
+    };
+    for (credential_mint, kyc_provider) in [
+        (mint, test.mint_authority.pubkey()),
+        (credential_mint.pubkey(), authority),
+    ] {
+        assert_eq!(
+            process_instructions(
+                &test.context,
+                &[set_credential_mint_ix(credential_mint, kyc_provider)],
+                &[],
+            )
+            .await
+            .unwrap_err(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(
+                    permissioned_token::ErrorCode::InvalidCredentialMint.into()
+                )
+            )
+        );
+    }
+    process_instructions(
+        &test.context,
+        &[set_credential_mint_ix(
+            credential_mint.pubkey(),
+            kyc_provider.pubkey(),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(2).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForReceiver,
+    );
+
+    credential
+        .mint_to(
+            &receiver_credential,
+            &kyc_provider.pubkey(),
+            1,
+            &[&kyc_provider],
+        )
+        .await
+        .unwrap();
+
+    // Both holders are accepted with their credentials, without a permission account
+    test.transfer(3).await.unwrap();
+
+    // A permission account takes precedence over the credential
+    process_instructions(
+        &test.context,
+        &[add_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            false,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_transfer_error(
+        test.transfer(5).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForSender,
+    );
+    process_instructions(
+        &test.context,
+        &[update_permission_ix(
+            &authority,
+            &test.permission_registry,
+            &test.sender.pubkey(),
+            true,
+            true,
+            i64::MAX,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(6).await.unwrap();
+
+    // The provider revokes a credential by freezing it
+    credential
+        .freeze(
+            &receiver_credential,
+            &kyc_provider.pubkey(),
+            &[&kyc_provider],
+        )
+        .await
+        .unwrap();
+    assert_transfer_error(
+        test.transfer(4).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForReceiver,
+    );
+}
+
+fn request_permission_ix(
+    owner: &Pubkey,
+    permission_registry: &Pubkey,
+    reference_hash: Option<[u8; 32]>,
+) -> Instruction {
+    Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::RequestPermission {
+            owner: *owner,
+            permission_registry: *permission_registry,
+            permission_request: permissioned_token::get_permission_request_address(
+                permission_registry,
+                owner,
+            ),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::RequestPermission {
+            allowed_send: true,
+            allowed_receive: true,
+            reference_hash,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn test_permission_requests() {
+    let test = TransferTest::new().await;
+    let authority = test.authority().await;
+    let sender_request = permissioned_token::get_permission_request_address(
+        &test.permission_registry,
+        &test.sender.pubkey(),
+    );
+
+    // Both holders pay for their requests
+    process_instructions(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &test.sender.pubkey(), 1_000_000_000),
+            system_instruction::transfer(&authority, &test.receiver.pubkey(), 1_000_000_000),
+            request_permission_ix(
+                &test.sender.pubkey(),
+                &test.permission_registry,
+                Some([1; 32]),
+            ),
+            request_permission_ix(&test.receiver.pubkey(), &test.permission_registry, None),
+        ],
+        &[&test.sender, &test.receiver],
+    )
+    .await
+    .unwrap();
+    let request: permissioned_token::PermissionRequest =
+        get_program_account(&test.context, sender_request).await;
+    assert_eq!(request.owner, test.sender.pubkey());
+    assert_eq!(request.reference_hash, Some([1; 32]));
+    assert_transfer_error(
+        test.transfer(1).await.unwrap_err(),
+        permissioned_token::ErrorCode::MissingPermissionForSender,
+    );
+
+    let approve_request_ix = |owner: Pubkey| Instruction {
+        program_id: permissioned_token::ID,
+        accounts: permissioned_token::accounts::ApproveRequest {
+            authority,
+            permission_registry: test.permission_registry,
+            role_registry: permissioned_token::get_role_registry_address(&test.permission_registry),
+            permission_request: permissioned_token::get_permission_request_address(
+                &test.permission_registry,
+                &owner,
+            ),
+            permission: permissioned_token::get_permission_address(
+                &test.permission_registry,
+                &owner,
+            ),
+            requester: owner,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: permissioned_token::instruction::ApproveRequest {
+            expire_at: i64::MAX,
+            owner,
+        }
+        .data(),
+    };
+    process_instructions(
+        &test.context,
+        &[
+            approve_request_ix(test.sender.pubkey()),
+            approve_request_ix(test.receiver.pubkey()),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    test.transfer(2).await.unwrap();
+
+    // A rejected request is closed and its rent refunded
+    let applicant = Keypair::new();
+    let applicant_request = permissioned_token::get_permission_request_address(
+        &test.permission_registry,
+        &applicant.pubkey(),
+    );
+    process_instructions(
+        &test.context,
+        &[
+            system_instruction::transfer(&authority, &applicant.pubkey(), 1_000_000_000),
+            request_permission_ix(&applicant.pubkey(), &test.permission_registry, None),
+        ],
+        &[&applicant],
+    )
+    .await
+    .unwrap();
+    let request_lamports = test
+        .context
+        .lock()
+        .await
+        .banks_client
+        .get_balance(applicant_request)
+        .await
+        .unwrap();
+    let applicant_lamports = test
+        .context
+        .lock()
+        .await
+        .banks_client
+        .get_balance(applicant.pubkey())
+        .await
+        .unwrap();
+    process_instructions(
+        &test.context,
+        &[Instruction {
+            program_id: permissioned_token::ID,
+            accounts: permissioned_token::accounts::RejectRequest {
+                authority,
+                permission_registry: test.permission_registry,
+                role_registry: permissioned_token::get_role_registry_address(
+                    &test.permission_registry,
+                ),
+                permission_request: applicant_request,
+                requester: applicant.pubkey(),
+            }
+            .to_account_metas(None),
+            data: permissioned_token::instruction::RejectRequest {
+                owner: applicant.pubkey(),
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+    let mut context = test.context.lock().await;
+    assert!(context
+        .banks_client
+        .get_account(applicant_request)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(applicant.pubkey())
+            .await
+            .unwrap(),
+        applicant_lamports + request_lamports
+    );
 }
@@ -8,7 +8,40 @@ pub mod signed_data {
     use super::*;
 
     pub fn initialize(ctx: Context<Initialize>, oracle_authority: Pubkey) -> Result<()> {
-        ctx.accounts.config.set_inner(Config { oracle_authority });
+        ctx.accounts.config.set_inner(Config {
+            oracle_authority,
+            pending_oracle_authority: None,
+        });
+        Ok(())
+    }
+
+    /// First step of rotating the oracle authority, the new one has to accept it
+    pub fn propose_authority(
+        ctx: Context<ProposeAuthority>,
+        new_oracle_authority: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.config.pending_oracle_authority = Some(new_oracle_authority);
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(
+            config.pending_oracle_authority == Some(ctx.accounts.new_oracle_authority.key()),
+            ErrorCode::IncorrectPendingAuthority
+        );
+        config.oracle_authority = ctx.accounts.new_oracle_authority.key();
+        config.pending_oracle_authority = None;
+        Ok(())
+    }
+
+    pub fn cancel_authority_transfer(ctx: Context<CancelAuthorityTransfer>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(
+            config.pending_oracle_authority.is_some(),
+            ErrorCode::NoPendingAuthority
+        );
+        config.pending_oracle_authority = None;
         Ok(())
     }
 
@@ -73,13 +106,34 @@ pub mod signed_data {
 
 #[derive(Accounts)]
 pub struct Initialize<'info> {
-    #[account(init, seeds = [b"config"], bump, payer = payer, space = 8 + 32)]
+    #[account(init, seeds = [b"config"], bump, payer = payer, space = 8 + 32 + 33)]
     config: Account<'info, Config>,
     #[account(mut)]
     payer: Signer<'info>,
     system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    #[account(mut, seeds = [b"config"], bump, has_one = oracle_authority)]
+    config: Account<'info, Config>,
+    oracle_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(mut, seeds = [b"config"], bump)]
+    config: Account<'info, Config>,
+    new_oracle_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAuthorityTransfer<'info> {
+    #[account(mut, seeds = [b"config"], bump, has_one = oracle_authority)]
+    config: Account<'info, Config>,
+    oracle_authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct ConsumeSignedData<'info> {
     #[account(seeds = [b"config"], bump)]
@@ -91,6 +145,7 @@ pub struct ConsumeSignedData<'info> {
 #[account]
 pub struct Config {
     oracle_authority: Pubkey,
+    pending_oracle_authority: Option<Pubkey>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -109,6 +164,8 @@ impl OracleData {
 pub enum ErrorCode {
     #[msg("")]
     InvalidDataOffsets,
+    NoPendingAuthority,
+    IncorrectPendingAuthority,
 }
 
 // Copied from solana monorepo to be accessible in program
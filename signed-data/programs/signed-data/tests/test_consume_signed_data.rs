@@ -2,7 +2,10 @@ use anchor_lang::{prelude::Pubkey, AnchorSerialize, InstructionData, ToAccountMe
 use signed_data;
 use solana_program_test::*;
 use solana_sdk::{
-    instruction::Instruction, signature::Keypair, signer::Signer, system_program, sysvar,
+    instruction::{Instruction, InstructionError},
+    signature::Keypair,
+    signer::Signer,
+    system_program, sysvar,
     transaction::Transaction,
 };
 mod ed25519_helper;
@@ -120,3 +123,123 @@ async fn test_consume_signed_data() {
         TransactionError::InvalidAccountIndex // Obscure precompile error
     );
 }
+
+#[tokio::test]
+async fn test_authority_transfer() {
+    let pt = ProgramTest::new("signed_data", signed_data::ID, None);
+
+    let oracle_authority_keypair = Keypair::new();
+    let new_oracle_authority_keypair = Keypair::new();
+    let new_oracle_authority = new_oracle_authority_keypair.pubkey();
+    let config = Pubkey::find_program_address(&[b"config"], &signed_data::ID).0;
+
+    let mut context = pt.start_with_context().await;
+    let payer = context.payer.pubkey();
+
+    process_transaction(
+        &mut context,
+        &[
+            Instruction {
+                program_id: signed_data::ID,
+                accounts: signed_data::accounts::Initialize {
+                    config,
+                    payer,
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: signed_data::instruction::Initialize {
+                    oracle_authority: oracle_authority_keypair.pubkey(),
+                }
+                .data(),
+            },
+            Instruction {
+                program_id: signed_data::ID,
+                accounts: signed_data::accounts::ProposeAuthority {
+                    config,
+                    oracle_authority: oracle_authority_keypair.pubkey(),
+                }
+                .to_account_metas(None),
+                data: signed_data::instruction::ProposeAuthority {
+                    new_oracle_authority,
+                }
+                .data(),
+            },
+        ],
+        &[&oracle_authority_keypair],
+    )
+    .await
+    .unwrap();
+
+    // Only the proposed authority can accept
+    let impostor = Keypair::new();
+    let result = process_transaction(
+        &mut context,
+        &[Instruction {
+            program_id: signed_data::ID,
+            accounts: signed_data::accounts::AcceptAuthority {
+                config,
+                new_oracle_authority: impostor.pubkey(),
+            }
+            .to_account_metas(None),
+            data: signed_data::instruction::AcceptAuthority.data(),
+        }],
+        &[&impostor],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(signed_data::ErrorCode::IncorrectPendingAuthority.into())
+        )
+    );
+
+    process_transaction(
+        &mut context,
+        &[Instruction {
+            program_id: signed_data::ID,
+            accounts: signed_data::accounts::AcceptAuthority {
+                config,
+                new_oracle_authority,
+            }
+            .to_account_metas(None),
+            data: signed_data::instruction::AcceptAuthority.data(),
+        }],
+        &[&new_oracle_authority_keypair],
+    )
+    .await
+    .unwrap();
+
+    // Data signed by the new authority is accepted
+    let oracle_data = signed_data::OracleData {
+        sequence_id: 101,
+        unix_timestamp: 13457,
+        price: 123456789,
+        mint: pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"),
+    };
+    let message = oracle_data.try_to_vec().unwrap();
+    let signature = new_oracle_authority_keypair.sign_message(&message);
+    process_transaction(
+        &mut context,
+        &[
+            ed25519_helper::new_ed25519_instruction_without_payload(&message, 1, 8),
+            Instruction {
+                program_id: signed_data::ID,
+                accounts: signed_data::accounts::ConsumeSignedData {
+                    config,
+                    instructions_sysvar: sysvar::instructions::ID,
+                }
+                .to_account_metas(None),
+                data: signed_data::instruction::ConsumeSignedData {
+                    signature: signature.into(),
+                    oracle_authority: new_oracle_authority,
+                    oracle_data,
+                }
+                .data(),
+            },
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+}
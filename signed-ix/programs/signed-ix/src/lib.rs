@@ -15,7 +15,70 @@ pub mod signed_ix {
     use super::*;
 
     pub fn initialize(ctx: Context<Initialize>, authority: [u8; 64]) -> Result<()> {
-        ctx.accounts.config.set_inner(Config { authority });
+        ctx.accounts.config.set_inner(Config {
+            authority,
+            pending_authority: None,
+            nonce: 0,
+        });
+        Ok(())
+    }
+
+    /// First step of rotating the authority, signed by the current authority
+    pub fn propose_authority(
+        ctx: Context<UpdateAuthority>,
+        new_authority: [u8; 64],
+        signature: [u8; 64],
+        recovery_id: u8,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let message_hash =
+            compute_authority_message_hash("propose_authority", &new_authority, config.nonce);
+        verify_signature(signature, recovery_id, &config.authority, message_hash)?;
+
+        config.pending_authority = Some(new_authority);
+        config.nonce += 1;
+        Ok(())
+    }
+
+    /// Second step of rotating the authority, signed by the new authority
+    pub fn accept_authority(
+        ctx: Context<UpdateAuthority>,
+        signature: [u8; 64],
+        recovery_id: u8,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let pending_authority = config
+            .pending_authority
+            .ok_or(ErrorCode::NoPendingAuthority)?;
+        let message_hash =
+            compute_authority_message_hash("accept_authority", &pending_authority, config.nonce);
+        verify_signature(signature, recovery_id, &pending_authority, message_hash)?;
+
+        config.authority = pending_authority;
+        config.pending_authority = None;
+        config.nonce += 1;
+        Ok(())
+    }
+
+    /// Drop the proposed authority, signed by the current authority
+    pub fn cancel_authority_transfer(
+        ctx: Context<UpdateAuthority>,
+        signature: [u8; 64],
+        recovery_id: u8,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let pending_authority = config
+            .pending_authority
+            .ok_or(ErrorCode::NoPendingAuthority)?;
+        let message_hash = compute_authority_message_hash(
+            "cancel_authority_transfer",
+            &pending_authority,
+            config.nonce,
+        );
+        verify_signature(signature, recovery_id, &config.authority, message_hash)?;
+
+        config.pending_authority = None;
+        config.nonce += 1;
         Ok(())
     }
 
@@ -76,6 +139,16 @@ pub fn compute_message_hash(request: &str, expire_at: i64, ix: &Instruction) ->
     hashv(&[&message_bytes])
 }
 
+/// Message signed to rotate the authority, the nonce prevents replaying a signature
+pub fn compute_authority_message_hash(action: &str, authority: &[u8; 64], nonce: u64) -> Hash {
+    hashv(&[
+        ID.as_ref(),
+        action.as_bytes(),
+        authority,
+        &nonce.to_le_bytes(),
+    ])
+}
+
 fn verify_signature(
     msg_signature: [u8; 64],
     recovery_id: u8,
@@ -113,6 +186,12 @@ pub struct Initialize<'info> {
     system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateAuthority<'info> {
+    #[account(mut, seeds = [b"config"], bump)]
+    config: Account<'info, Config>,
+}
+
 #[derive(Accounts)]
 pub struct VerifySignedIx<'info> {
     #[account(seeds = [b"config"], bump)]
@@ -126,10 +205,14 @@ pub struct VerifySignedIx<'info> {
 pub struct Config {
     // a secp256k1 pubkey
     authority: [u8; 64],
+    pending_authority: Option<[u8; 64]>,
+    // incremented on each authority update
+    nonce: u64,
 }
 
 #[error_code]
 pub enum ErrorCode {
     EncounteredSignatureWithHighSValue,
     SignatureDoesNotMatchAuthority,
+    NoPendingAuthority,
 }
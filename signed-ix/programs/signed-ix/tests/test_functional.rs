@@ -166,3 +166,152 @@ async fn test_signed_ix() {
         TransactionError::InstructionError(1, InstructionError::Custom(6001))
     );
 }
+
+fn new_secp256k1_authority() -> (libsecp256k1::SecretKey, [u8; 64]) {
+    let mut rng = rand::thread_rng();
+    let secret_key = libsecp256k1::SecretKey::random(&mut rng);
+    let mut public_key_bytes = [0; 64];
+    public_key_bytes.copy_from_slice(&PublicKey::from_secret_key(&secret_key).serialize()[1..]);
+    (secret_key, public_key_bytes)
+}
+
+fn sign_authority_message(
+    secret_key: &libsecp256k1::SecretKey,
+    action: &str,
+    authority: &[u8; 64],
+    nonce: u64,
+) -> ([u8; 64], u8) {
+    let message_hash = signed_ix::compute_authority_message_hash(action, authority, nonce);
+    let secp_message = libsecp256k1::Message::parse(&message_hash.to_bytes());
+    let (signature, recovery_id) = libsecp256k1::sign(&secp_message, secret_key);
+    (signature.serialize(), recovery_id.serialize())
+}
+
+#[tokio::test]
+async fn test_authority_transfer() {
+    let program_id = signed_ix::ID;
+    let mut program_test = ProgramTest::new("signed_ix", program_id, None);
+    program_test.prefer_bpf(true);
+    let mut context = program_test.start_with_context().await;
+
+    let (secret_key, authority) = new_secp256k1_authority();
+    let (new_secret_key, new_authority) = new_secp256k1_authority();
+    let payer = context.payer.pubkey();
+    let config = Pubkey::find_program_address(&[b"config"], &signed_ix::ID).0;
+    let update_authority_accounts = signed_ix::accounts::UpdateAuthority { config };
+
+    let (signature, recovery_id) =
+        sign_authority_message(&secret_key, "propose_authority", &new_authority, 0);
+    let propose_ix = Instruction {
+        program_id: signed_ix::ID,
+        accounts: update_authority_accounts.to_account_metas(None),
+        data: signed_ix::instruction::ProposeAuthority {
+            new_authority,
+            signature,
+            recovery_id,
+        }
+        .data(),
+    };
+    process_transaction(
+        &mut context,
+        &[
+            Instruction {
+                program_id: signed_ix::ID,
+                accounts: signed_ix::accounts::Initialize {
+                    config,
+                    payer,
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: signed_ix::instruction::Initialize { authority }.data(),
+            },
+            propose_ix.clone(),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // The proposal cannot be replayed once the nonce moved
+    context.get_new_latest_blockhash().await.unwrap();
+    let result = process_transaction(&mut context, &[propose_ix], &[]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(signed_ix::ErrorCode::SignatureDoesNotMatchAuthority.into())
+        )
+    );
+
+    // The current authority cannot accept in place of the new one
+    let (signature, recovery_id) =
+        sign_authority_message(&secret_key, "accept_authority", &new_authority, 1);
+    let result = process_transaction(
+        &mut context,
+        &[Instruction {
+            program_id: signed_ix::ID,
+            accounts: update_authority_accounts.to_account_metas(None),
+            data: signed_ix::instruction::AcceptAuthority {
+                signature,
+                recovery_id,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(signed_ix::ErrorCode::SignatureDoesNotMatchAuthority.into())
+        )
+    );
+
+    let (signature, recovery_id) =
+        sign_authority_message(&new_secret_key, "accept_authority", &new_authority, 1);
+    process_transaction(
+        &mut context,
+        &[Instruction {
+            program_id: signed_ix::ID,
+            accounts: update_authority_accounts.to_account_metas(None),
+            data: signed_ix::instruction::AcceptAuthority {
+                signature,
+                recovery_id,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Nothing left to cancel
+    let (signature, recovery_id) = sign_authority_message(
+        &new_secret_key,
+        "cancel_authority_transfer",
+        &new_authority,
+        2,
+    );
+    let result = process_transaction(
+        &mut context,
+        &[Instruction {
+            program_id: signed_ix::ID,
+            accounts: update_authority_accounts.to_account_metas(None),
+            data: signed_ix::instruction::CancelAuthorityTransfer {
+                signature,
+                recovery_id,
+            }
+            .data(),
+        }],
+        &[],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(signed_ix::ErrorCode::NoPendingAuthority.into())
+        )
+    );
+}